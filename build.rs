@@ -0,0 +1,23 @@
+use std::fs;
+
+// Exposes the versions of the embedded migrations, in the form diesel records them, so that the
+// readiness check can tell whether the database is behind the binary.
+fn main() {
+    println!("cargo:rerun-if-changed=migrations");
+    let mut versions: Vec<String> = fs::read_dir("migrations")
+        .expect("Failed to read the migrations directory.")
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            let version = name.split('_').next()?.replace('-', "");
+            if version.is_empty() || !version.bytes().all(|byte| byte.is_ascii_digit()) {
+                return None;
+            }
+            Some(version)
+        })
+        .collect();
+    versions.sort();
+    println!(
+        "cargo:rustc-env=WT_MIGRATION_VERSIONS={}",
+        versions.join(",")
+    );
+}
@@ -0,0 +1,2 @@
+DROP TABLE moderation_logs;
+ALTER TABLE users DROP COLUMN role;
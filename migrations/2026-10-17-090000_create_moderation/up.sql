@@ -0,0 +1,21 @@
+ALTER TABLE public.users
+    ADD COLUMN role smallint NOT NULL DEFAULT 0;
+
+CREATE TABLE public.moderation_logs(
+    id bigserial NOT NULL,
+    moderator_id bigint NOT NULL,
+    action smallint NOT NULL,
+    target_comment_id bigint,
+    target_user_id bigint,
+    "timestamp" bigint NOT NULL,
+    PRIMARY KEY (id),
+    CONSTRAINT moderator_id_fkey FOREIGN KEY (moderator_id)
+        REFERENCES public.users (id),
+    CONSTRAINT target_comment_id_fkey FOREIGN KEY (target_comment_id)
+        REFERENCES public.comments (id),
+    CONSTRAINT target_user_id_fkey FOREIGN KEY (target_user_id)
+        REFERENCES public.users (id)
+);
+
+CREATE INDEX moderation_logs_timestamp_index
+    ON public.moderation_logs USING btree ("timestamp");
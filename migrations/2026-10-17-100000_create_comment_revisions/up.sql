@@ -0,0 +1,12 @@
+CREATE TABLE public.comment_revisions(
+    id bigserial NOT NULL,
+    comment_id bigint NOT NULL,
+    content varchar(4096) NOT NULL,
+    "timestamp" bigint NOT NULL,
+    PRIMARY KEY (id),
+    CONSTRAINT comment_id_fkey FOREIGN KEY (comment_id)
+        REFERENCES public.comments (id)
+);
+
+CREATE INDEX comment_revisions_comment_id_index
+    ON public.comment_revisions USING btree (comment_id);
@@ -0,0 +1,7 @@
+ALTER TABLE public.comments
+    ADD COLUMN parent_comment_id bigint,
+    ADD CONSTRAINT parent_comment_id_fkey FOREIGN KEY (parent_comment_id)
+        REFERENCES public.comments (id);
+
+CREATE INDEX comments_parent_comment_id_index
+    ON public.comments USING btree (parent_comment_id);
@@ -0,0 +1 @@
+DROP INDEX comments_chapter_id_id_index;
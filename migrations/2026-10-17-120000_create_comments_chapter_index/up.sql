@@ -0,0 +1,2 @@
+CREATE INDEX comments_chapter_id_id_index
+    ON public.comments USING btree (chapter_id, id DESC);
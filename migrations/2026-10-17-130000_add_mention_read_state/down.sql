@@ -0,0 +1 @@
+ALTER TABLE mentions DROP COLUMN read;
@@ -0,0 +1,12 @@
+ALTER TABLE public.mentions
+    ADD COLUMN read bool NOT NULL DEFAULT FALSE;
+
+-- Everything older than the last time a user checked their mentions counts as read.
+UPDATE public.mentions
+    SET read = TRUE
+    FROM public.users
+    WHERE users.id = mentions.mentioned_user_id
+        AND mentions."timestamp" < users.last_checked_mentions_timestamp;
+
+CREATE INDEX mentions_mentioned_user_id_read_index
+    ON public.mentions USING btree (mentioned_user_id, read);
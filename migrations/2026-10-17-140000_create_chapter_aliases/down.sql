@@ -0,0 +1 @@
+DROP TABLE chapter_aliases;
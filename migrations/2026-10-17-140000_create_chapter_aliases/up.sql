@@ -0,0 +1,10 @@
+CREATE TABLE public.chapter_aliases(
+    relative_path character varying(1024) NOT NULL,
+    chapter_id integer NOT NULL,
+    PRIMARY KEY (relative_path),
+    CONSTRAINT chapter_id_fkey FOREIGN KEY (chapter_id)
+        REFERENCES public.chapters (id)
+);
+
+CREATE INDEX chapter_aliases_chapter_id_index
+    ON public.chapter_aliases USING btree (chapter_id);
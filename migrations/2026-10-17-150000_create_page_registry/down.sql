@@ -0,0 +1,2 @@
+DROP TABLE quarantined_pages;
+DROP TABLE page_patterns;
@@ -0,0 +1,12 @@
+CREATE TABLE public.page_patterns(
+    pattern character varying(1024) NOT NULL,
+    PRIMARY KEY (pattern)
+);
+
+CREATE TABLE public.quarantined_pages(
+    relative_path character varying(1024) NOT NULL,
+    visit_count bigint NOT NULL DEFAULT 0,
+    first_visit_timestamp bigint NOT NULL,
+    last_visit_timestamp bigint NOT NULL,
+    PRIMARY KEY (relative_path)
+);
@@ -0,0 +1 @@
+DROP INDEX visits_chapter_id_timestamp_index;
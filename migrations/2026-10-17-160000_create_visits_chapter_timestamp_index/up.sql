@@ -0,0 +1,3 @@
+CREATE INDEX visits_chapter_id_timestamp_index
+    ON public.visits USING btree
+        (chapter_id, "timestamp");
@@ -0,0 +1,3 @@
+DROP TABLE public.rollup_watermarks;
+DROP TABLE public.visit_rollups_daily;
+DROP TABLE public.visit_rollups_hourly;
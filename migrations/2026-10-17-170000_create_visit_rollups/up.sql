@@ -0,0 +1,33 @@
+CREATE TABLE public.visit_rollups_hourly(
+    chapter_id integer NOT NULL,
+    bucket_start bigint NOT NULL,
+    visit_count bigint NOT NULL,
+    PRIMARY KEY (chapter_id, bucket_start),
+    CONSTRAINT chapter_id_fkey FOREIGN KEY (chapter_id)
+        REFERENCES public.chapters (id)
+);
+
+CREATE INDEX visit_rollups_hourly_bucket_start_index
+    ON public.visit_rollups_hourly USING btree (bucket_start);
+
+CREATE TABLE public.visit_rollups_daily(
+    chapter_id integer NOT NULL,
+    bucket_start bigint NOT NULL,
+    visit_count bigint NOT NULL,
+    PRIMARY KEY (chapter_id, bucket_start),
+    CONSTRAINT chapter_id_fkey FOREIGN KEY (chapter_id)
+        REFERENCES public.chapters (id)
+);
+
+CREATE INDEX visit_rollups_daily_bucket_start_index
+    ON public.visit_rollups_daily USING btree (bucket_start);
+
+-- Everything before rolled_up_until has been added to the rollup; starting from 0 makes the first
+-- run backfill all existing visits.
+CREATE TABLE public.rollup_watermarks(
+    name character varying(16) NOT NULL,
+    rolled_up_until bigint NOT NULL,
+    PRIMARY KEY (name)
+);
+
+INSERT INTO public.rollup_watermarks (name, rolled_up_until) VALUES ('hourly', 0), ('daily', 0);
@@ -0,0 +1,8 @@
+CREATE TABLE public.visits_archive(
+    id bigint NOT NULL,
+    chapter_id integer NOT NULL,
+    "timestamp" bigint NOT NULL,
+    PRIMARY KEY (id),
+    CONSTRAINT chapter_id_fkey FOREIGN KEY (chapter_id)
+        REFERENCES public.chapters (id)
+);
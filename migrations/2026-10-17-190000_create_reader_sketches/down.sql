@@ -0,0 +1,2 @@
+DROP TABLE public.site_reader_sketches;
+DROP TABLE public.reader_sketches;
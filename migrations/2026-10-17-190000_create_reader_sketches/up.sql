@@ -0,0 +1,14 @@
+CREATE TABLE public.reader_sketches(
+    chapter_id integer NOT NULL,
+    day_start bigint NOT NULL,
+    registers bytea NOT NULL,
+    PRIMARY KEY (chapter_id, day_start),
+    CONSTRAINT chapter_id_fkey FOREIGN KEY (chapter_id)
+        REFERENCES public.chapters (id)
+);
+
+CREATE TABLE public.site_reader_sketches(
+    day_start bigint NOT NULL,
+    registers bytea NOT NULL,
+    PRIMARY KEY (day_start)
+);
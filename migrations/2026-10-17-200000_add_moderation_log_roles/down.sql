@@ -0,0 +1,3 @@
+ALTER TABLE public.moderation_logs DROP COLUMN role;
+DELETE FROM public.moderation_logs WHERE moderator_id IS NULL;
+ALTER TABLE public.moderation_logs ALTER COLUMN moderator_id SET NOT NULL;
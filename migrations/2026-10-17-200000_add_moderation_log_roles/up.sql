@@ -0,0 +1,7 @@
+-- Actions taken through wt_admin are logged without a moderator.
+ALTER TABLE public.moderation_logs
+    ALTER COLUMN moderator_id DROP NOT NULL;
+
+-- The role given to target_user_id by a role change.
+ALTER TABLE public.moderation_logs
+    ADD COLUMN role smallint;
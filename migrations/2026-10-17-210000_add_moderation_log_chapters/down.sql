@@ -0,0 +1,3 @@
+ALTER TABLE public.moderation_logs
+    DROP COLUMN from_chapter_path,
+    DROP COLUMN into_chapter_path;
@@ -0,0 +1,5 @@
+-- The paths of merged chapters. The chapter merged away is deleted, so its id would not say which
+-- chapter it was.
+ALTER TABLE public.moderation_logs
+    ADD COLUMN from_chapter_path character varying,
+    ADD COLUMN into_chapter_path character varying;
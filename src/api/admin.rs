@@ -0,0 +1,664 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::str::FromStr;
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::http::header;
+use actix_web::web::Bytes;
+use actix_web::{post, web, Either, HttpResponse, Responder};
+use diesel::prelude::*;
+use diesel::result::Error;
+use diesel::{insert_into, update};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+
+use crate::api::comment::{
+    convert_comment_query_results_to_response, SingleCommentQueryResult, SingleCommentResponse,
+};
+use crate::api::common::{APIResult, ErrorCode};
+use crate::api::{common, user};
+use crate::error::WTError;
+use crate::export::{Export, ExportData, ExportFormat};
+use crate::metrics;
+use crate::models::{Chapter, Comment, ModerationLog, User};
+use crate::pages::{self, Pages, UnknownPages};
+use crate::response_cache::CacheGroup;
+use crate::schema::{chapters, comments, moderation_logs, page_patterns, quarantined_pages, users};
+use crate::{AppState, DbConnection};
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    User = 0,
+    Moderator = 1,
+    Admin = 2,
+}
+
+impl Role {
+    pub fn of(user: &User) -> Role {
+        match user.role {
+            2 => Role::Admin,
+            1 => Role::Moderator,
+            _ => Role::User,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::User => "user",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "user" => Ok(Role::User),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err("expected user, moderator or admin".to_owned()),
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub enum ModerationAction {
+    DeleteComment = 1,
+    RestoreComment = 2,
+    DisableUser = 3,
+    EnableUser = 4,
+    MergeChapters = 5,
+    SetRole = 6,
+    SetPagePatterns = 7,
+}
+
+/// What a moderation action was taken on.
+pub enum ModerationTarget {
+    Comment(i64),
+    User(i64),
+    /// A user and the role they were given.
+    Role(i64, Role),
+    /// The path of the chapter merged away and of the one it was merged into.
+    Chapters {
+        from: String,
+        into: String,
+    },
+    /// The stored page patterns as a whole.
+    PagePatterns,
+}
+
+/// Returns the user behind `token` if their account is active and holds at least `required`.
+fn authorize(
+    connection: &DbConnection,
+    token: &str,
+    required: Role,
+) -> Result<Option<User>, Error> {
+    Ok(user::get_user(connection, token)?
+        .found()
+        .filter(|user| Role::of(user) >= required))
+}
+
+/// Records `action` in `moderation_logs`. `moderator_id` is `None` for actions taken through
+/// wt_admin.
+pub fn log_action(
+    connection: &DbConnection,
+    moderator_id: Option<i64>,
+    action: ModerationAction,
+    target: ModerationTarget,
+) -> Result<(), Error> {
+    let (target_comment_id, target_user_id, role, chapter_paths) = match target {
+        ModerationTarget::Comment(comment_id) => (Some(comment_id), None, None, None),
+        ModerationTarget::User(user_id) => (None, Some(user_id), None, None),
+        ModerationTarget::Role(user_id, role) => (None, Some(user_id), Some(role as i16), None),
+        ModerationTarget::Chapters { from, into } => (None, None, None, Some((from, into))),
+        ModerationTarget::PagePatterns => (None, None, None, None),
+    };
+    let (from_chapter_path, into_chapter_path) = chapter_paths.unzip();
+    insert_into(moderation_logs::table)
+        .values((
+            moderation_logs::moderator_id.eq(moderator_id),
+            moderation_logs::action.eq(action as i16),
+            moderation_logs::target_comment_id.eq(target_comment_id),
+            moderation_logs::target_user_id.eq(target_user_id),
+            moderation_logs::timestamp.eq(common::get_current_timestamp()),
+            moderation_logs::role.eq(role),
+            moderation_logs::from_chapter_path.eq(from_chapter_path),
+            moderation_logs::into_chapter_path.eq(into_chapter_path),
+        ))
+        .execute(connection)?;
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct CommentActionPayload {
+    token: String,
+    comment_id: i64,
+}
+
+fn set_comment_deleted<TCon: Deref<Target = DbConnection>>(
+    connection: TCon,
+    token: String,
+    comment_id: i64,
+    deleted: bool,
+) -> Result<APIResult, WTError> {
+    let moderator = match authorize(&connection, &token, Role::Moderator)? {
+        Some(moderator) => moderator,
+        None => return Ok(APIResult::forbidden()),
+    };
+    connection.transaction::<APIResult, WTError, _>(|| {
+        let current: Option<bool> = comments::table
+            .filter(comments::id.eq(comment_id))
+            .select(comments::deleted)
+            .for_update()
+            .first(&*connection)
+            .optional()?;
+        match current {
+            None => return Ok(APIResult::error(ErrorCode::CommentNotFound)),
+            Some(current) if current == deleted => return Ok(APIResult::success()),
+            Some(_) => {}
+        }
+        update(comments::table)
+            .filter(comments::id.eq(comment_id))
+            .set(comments::deleted.eq(deleted))
+            .execute(&*connection)?;
+        let action = if deleted {
+            ModerationAction::DeleteComment
+        } else {
+            ModerationAction::RestoreComment
+        };
+        log_action(
+            &connection,
+            Some(moderator.id),
+            action,
+            ModerationTarget::Comment(comment_id),
+        )?;
+        if deleted {
+            metrics::COMMENTS_DELETED.inc();
+        }
+        Ok(APIResult::success())
+    })
+}
+
+#[post("/deleteComment")]
+async fn delete_comment_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<CommentActionPayload>,
+) -> Result<impl Responder, WTError> {
+    let connection = state.db_pool.get()?;
+    let result = common::block(move || {
+        set_comment_deleted(connection, payload.0.token, payload.0.comment_id, true)
+    })
+    .await??;
+    if let APIResult::Success(_) = result {
+        state.response_cache.invalidate(CacheGroup::Comments);
+    }
+    Ok(result.into_responder())
+}
+
+#[post("/restoreComment")]
+async fn restore_comment_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<CommentActionPayload>,
+) -> Result<impl Responder, WTError> {
+    let connection = state.db_pool.get()?;
+    let result = common::block(move || {
+        set_comment_deleted(connection, payload.0.token, payload.0.comment_id, false)
+    })
+    .await??;
+    if let APIResult::Success(_) = result {
+        state.response_cache.invalidate(CacheGroup::Comments);
+    }
+    Ok(result.into_responder())
+}
+
+#[derive(Deserialize)]
+struct UserActionPayload {
+    token: String,
+    user_name: String,
+}
+
+fn set_user_disabled<TCon: Deref<Target = DbConnection>>(
+    connection: TCon,
+    token: String,
+    user_name: String,
+    disabled: bool,
+) -> Result<APIResult, WTError> {
+    let admin = match authorize(&connection, &token, Role::Admin)? {
+        Some(admin) => admin,
+        None => return Ok(APIResult::forbidden()),
+    };
+    connection.transaction::<APIResult, WTError, _>(|| {
+        let target: Option<User> = users::table
+            .filter(users::user_name.eq(&user_name))
+            .for_update()
+            .first(&*connection)
+            .optional()?;
+        let target = match target {
+            Some(target) => target,
+            None => return Ok(APIResult::error(ErrorCode::UserNotFound)),
+        };
+        if Role::of(&target) >= Role::of(&admin) {
+            return Ok(APIResult::forbidden());
+        }
+        if target.disabled == disabled {
+            return Ok(APIResult::success());
+        }
+        diesel::update(&target)
+            .set(users::disabled.eq(disabled))
+            .execute(&*connection)?;
+        let action = if disabled {
+            ModerationAction::DisableUser
+        } else {
+            ModerationAction::EnableUser
+        };
+        log_action(
+            &connection,
+            Some(admin.id),
+            action,
+            ModerationTarget::User(target.id),
+        )?;
+        Ok(APIResult::success())
+    })
+}
+
+#[post("/disableUser")]
+async fn disable_user_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<UserActionPayload>,
+) -> Result<impl Responder, WTError> {
+    let connection = state.db_pool.get()?;
+    Ok(common::block(move || {
+        set_user_disabled(connection, payload.0.token, payload.0.user_name, true)
+    })
+    .await??
+    .into_responder())
+}
+
+#[post("/enableUser")]
+async fn enable_user_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<UserActionPayload>,
+) -> Result<impl Responder, WTError> {
+    let connection = state.db_pool.get()?;
+    Ok(common::block(move || {
+        set_user_disabled(connection, payload.0.token, payload.0.user_name, false)
+    })
+    .await??
+    .into_responder())
+}
+
+#[derive(Deserialize)]
+struct MergeChaptersPayload {
+    token: String,
+    from: String,
+    into: String,
+}
+
+#[derive(Serialize)]
+struct MergeChaptersResponse {
+    visits: usize,
+    comments: usize,
+}
+
+fn merge_chapters<TCon: Deref<Target = DbConnection>>(
+    connection: TCon,
+    payload: MergeChaptersPayload,
+) -> Result<APIResult<MergeChaptersResponse>, WTError> {
+    let admin = match authorize(&connection, &payload.token, Role::Admin)? {
+        Some(admin) => admin,
+        None => return Ok(APIResult::forbidden()),
+    };
+    connection.transaction::<_, WTError, _>(|| {
+        let find = |relative_path: &str| {
+            chapters::table
+                .filter(chapters::relative_path.eq(relative_path))
+                .for_update()
+                .first::<Chapter>(&*connection)
+                .optional()
+        };
+        let (from, into) = match (find(&payload.from)?, find(&payload.into)?) {
+            (Some(from), Some(into)) => (from, into),
+            _ => return Ok(APIResult::error(ErrorCode::ChapterNotFound)),
+        };
+        if from.id == into.id {
+            return Ok(APIResult::error(ErrorCode::ValidationFailed));
+        }
+        let counts = common::merge_chapters(&connection, &from, &into)?;
+        log_action(
+            &connection,
+            Some(admin.id),
+            ModerationAction::MergeChapters,
+            ModerationTarget::Chapters {
+                from: from.relative_path.clone(),
+                into: into.relative_path.clone(),
+            },
+        )?;
+        Ok(APIResult::success_return(MergeChaptersResponse {
+            visits: counts.visits,
+            comments: counts.comments,
+        }))
+    })
+}
+
+/// Merges the chapter at `from` into the one at `into`; `from` keeps resolving as an alias.
+#[post("/mergeChapters")]
+async fn merge_chapters_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<MergeChaptersPayload>,
+) -> Result<impl Responder, WTError> {
+    let connection = state.db_pool.get()?;
+    let result = common::block(move || merge_chapters(connection, payload.0)).await??;
+    if let APIResult::Success(_) = result {
+        state.response_cache.invalidate(CacheGroup::Chapters);
+        state.response_cache.invalidate(CacheGroup::Comments);
+    }
+    Ok(result.into_responder())
+}
+
+#[derive(Deserialize)]
+struct GetRecentDeletionsPayload {
+    token: String,
+}
+
+#[derive(Serialize)]
+struct SingleDeletionResponse {
+    timestamp: i64,
+    moderator_user_name: String,
+    restored: bool,
+    comment: SingleCommentResponse,
+}
+
+fn get_recent_deletions<TCon: Deref<Target = DbConnection>>(
+    connection: TCon,
+    token: String,
+    amount: i64,
+) -> Result<Option<Vec<SingleDeletionResponse>>, WTError> {
+    if authorize(&connection, &token, Role::Moderator)?.is_none() {
+        return Ok(None);
+    }
+    let rows: Vec<(ModerationLog, String, Comment, User)> = moderation_logs::table
+        .inner_join(
+            comments::table
+                .inner_join(chapters::table)
+                .inner_join(users::table),
+        )
+        .select((
+            moderation_logs::table::all_columns(),
+            chapters::relative_path,
+            comments::table::all_columns(),
+            users::table::all_columns(),
+        ))
+        .filter(moderation_logs::action.eq(ModerationAction::DeleteComment as i16))
+        .order_by(moderation_logs::id.desc())
+        .limit(amount)
+        .load(&*connection)?;
+    let moderator_names: HashMap<i64, String> = users::table
+        .filter(users::id.eq_any(rows.iter().filter_map(|(log, ..)| log.moderator_id)))
+        .select((users::id, users::user_name))
+        .load(&*connection)?
+        .into_iter()
+        .collect();
+    let mut logs = Vec::with_capacity(rows.len());
+    let mut comments = Vec::with_capacity(rows.len());
+    for (log, relative_path, comment, user) in rows {
+        logs.push((log, !comment.deleted));
+        comments.push(SingleCommentQueryResult {
+            relative_path,
+            comment,
+            user,
+        });
+    }
+    Ok(Some(
+        logs.into_iter()
+            .zip(convert_comment_query_results_to_response(comments))
+            .map(|((log, restored), comment)| SingleDeletionResponse {
+                timestamp: log.timestamp,
+                // Empty for comments deleted through wt_admin.
+                moderator_user_name: log
+                    .moderator_id
+                    .and_then(|moderator_id| moderator_names.get(&moderator_id))
+                    .cloned()
+                    .unwrap_or_default(),
+                restored,
+                comment,
+            })
+            .collect(),
+    ))
+}
+
+#[post("/getRecentDeletions")]
+async fn get_recent_deletions_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<GetRecentDeletionsPayload>,
+) -> Result<impl Responder, WTError> {
+    let amount = state.config.comment.recent_comments_amount;
+    let connection = state.db_pool.get()?;
+    match common::block(move || get_recent_deletions(connection, payload.0.token, amount)).await?? {
+        Some(results) => Ok(Either::Left(HttpResponse::Ok().json(results))),
+        None => Ok(Either::Right(HttpResponse::Forbidden())),
+    }
+}
+
+#[derive(Deserialize)]
+struct TokenPayload {
+    token: String,
+}
+
+#[derive(Serialize)]
+struct PagePatternsResponse {
+    unknown: UnknownPages,
+    /// From the configuration file and manifest; read-only here.
+    configured: Vec<String>,
+    stored: Vec<String>,
+}
+
+#[post("/getPagePatterns")]
+async fn get_page_patterns_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<TokenPayload>,
+) -> Result<impl Responder, WTError> {
+    let connection = state.db_pool.get()?;
+    let stored = common::block(move || -> Result<Option<Vec<String>>, WTError> {
+        if authorize(&connection, &payload.token, Role::Admin)?.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(pages::load_stored_patterns(&connection)?))
+    })
+    .await??;
+    Ok(match stored {
+        Some(stored) => APIResult::success_return(PagePatternsResponse {
+            unknown: state.config.pages.unknown,
+            configured: state.pages.configured_patterns().to_vec(),
+            stored,
+        }),
+        None => APIResult::forbidden(),
+    }
+    .into_responder())
+}
+
+#[derive(Deserialize)]
+struct SetPagePatternsPayload {
+    token: String,
+    patterns: Vec<String>,
+}
+
+fn set_page_patterns<TCon: Deref<Target = DbConnection>>(
+    connection: TCon,
+    pages: &Pages,
+    token: String,
+    patterns: Vec<String>,
+) -> Result<APIResult, WTError> {
+    let admin = match authorize(&connection, &token, Role::Admin)? {
+        Some(admin) => admin,
+        None => return Ok(APIResult::forbidden()),
+    };
+    if patterns.iter().any(|pattern| {
+        pattern.is_empty()
+            || pattern.len() > common::MAX_PAGE_NAME_BYTES
+            || !pages::is_valid_pattern(pattern)
+    }) {
+        return Ok(APIResult::error(ErrorCode::ValidationFailed));
+    }
+    // Patterns that compile on their own may still exceed the size limit of the whole registry.
+    let registry = pages.with_stored_patterns(&patterns).map_err(|error| {
+        WTError::Validation(format!("The page patterns are invalid: {}", error))
+    })?;
+    connection.transaction::<_, WTError, _>(|| {
+        diesel::delete(page_patterns::table).execute(&*connection)?;
+        insert_into(page_patterns::table)
+            .values(
+                patterns
+                    .iter()
+                    .map(|pattern| page_patterns::pattern.eq(pattern))
+                    .collect::<Vec<_>>(),
+            )
+            .on_conflict_do_nothing()
+            .execute(&*connection)?;
+        log_action(
+            &connection,
+            Some(admin.id),
+            ModerationAction::SetPagePatterns,
+            ModerationTarget::PagePatterns,
+        )?;
+        Ok(())
+    })?;
+    pages.replace(registry);
+    Ok(APIResult::success())
+}
+
+/// Replaces the stored page patterns. Patterns from the configuration are kept regardless. Other
+/// instances pick the new patterns up within `pages.reload_interval_seconds`.
+#[post("/setPagePatterns")]
+async fn set_page_patterns_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<SetPagePatternsPayload>,
+) -> Result<impl Responder, WTError> {
+    let pages = state.pages.clone();
+    let connection = state.db_pool.get()?;
+    Ok(common::block(move || {
+        set_page_patterns(connection, &pages, payload.0.token, payload.0.patterns)
+    })
+    .await??
+    .into_responder())
+}
+
+#[derive(Serialize, Queryable)]
+struct QuarantinedPageResponse {
+    relative_path: String,
+    visit_count: i64,
+    first_visit_timestamp: i64,
+    last_visit_timestamp: i64,
+}
+
+#[derive(Serialize)]
+struct QuarantinedPagesResponse {
+    pages: Vec<QuarantinedPageResponse>,
+}
+
+/// Lists the most visited paths that were quarantined for matching no page pattern.
+#[post("/getQuarantinedPages")]
+async fn get_quarantined_pages_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<TokenPayload>,
+) -> Result<impl Responder, WTError> {
+    let amount = state.config.analytics.page_size;
+    let connection = state.db_pool.get()?;
+    Ok(common::block(
+        move || -> Result<APIResult<QuarantinedPagesResponse>, WTError> {
+            if authorize(&connection, &payload.token, Role::Moderator)?.is_none() {
+                return Ok(APIResult::forbidden());
+            }
+            let pages = quarantined_pages::table
+                .order_by(quarantined_pages::visit_count.desc())
+                .limit(amount.into())
+                .load(&connection)?;
+            Ok(APIResult::success_return(QuarantinedPagesResponse {
+                pages,
+            }))
+        },
+    )
+    .await??
+    .into_responder())
+}
+
+#[derive(Deserialize)]
+struct ExportPayload {
+    token: String,
+    data: ExportData,
+    format: ExportFormat,
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+/// Streams chapter totals, daily rollups or raw visits as CSV or JSON Lines, reading one chunk
+/// from the database at a time. `from` and `to` are timestamps in milliseconds.
+#[post("/export")]
+async fn export_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<ExportPayload>,
+) -> Result<Either<HttpResponse, impl Responder>, WTError> {
+    let payload = payload.0;
+    let connection = state.db_pool.get()?;
+    let token = payload.token;
+    if common::block(move || authorize(&connection, &token, Role::Admin))
+        .await??
+        .is_none()
+    {
+        return Ok(Either::Right(APIResult::<()>::forbidden().into_responder()));
+    }
+    let export = Export::new(payload.data, payload.format, payload.from, payload.to)
+        .map_err(WTError::Validation)?;
+    let db_pool = state.db_pool.clone();
+    let chunks = stream::try_unfold(export, move |mut export| {
+        let db_pool = db_pool.clone();
+        async move {
+            common::block(move || -> Result<_, WTError> {
+                let connection = db_pool.get()?;
+                Ok(export
+                    .next_chunk(&connection)?
+                    .map(|chunk| (Bytes::from(chunk), export)))
+            })
+            .await?
+            .map_err(|error| {
+                // The status line is already sent, so the response can only be cut short.
+                tracing::error!(error = %error, cause = ?error.cause(), "export failed");
+                actix_web::Error::from(error)
+            })
+        }
+    });
+    let file_name = match payload.data {
+        ExportData::Chapters => "chapters",
+        ExportData::Daily => "daily",
+        ExportData::Visits => "visits",
+    };
+    Ok(Either::Left(
+        HttpResponse::Ok()
+            .content_type(payload.format.content_type())
+            .insert_header((
+                header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"{}.{}\"",
+                    file_name,
+                    payload.format.extension()
+                ),
+            ))
+            .streaming(chunks),
+    ))
+}
+
+pub fn get_service() -> impl HttpServiceFactory {
+    web::scope("/admin")
+        .service(delete_comment_handler)
+        .service(restore_comment_handler)
+        .service(disable_user_handler)
+        .service(enable_user_handler)
+        .service(get_recent_deletions_handler)
+        .service(merge_chapters_handler)
+        .service(get_page_patterns_handler)
+        .service(set_page_patterns_handler)
+        .service(get_quarantined_pages_handler)
+        .service(export_handler)
+}
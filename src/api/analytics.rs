@@ -1,21 +1,32 @@
+use std::collections::HashMap;
+
 use actix_web::dev::HttpServiceFactory;
-use actix_web::{get, post, web, Either, HttpResponse, Responder};
+use actix_web::http::header;
+use actix_web::{get, post, web, Either, HttpRequest, HttpResponse, Responder};
 use diesel::prelude::*;
-use diesel::sql_types::{BigInt, Bigint, VarChar};
-use diesel::{insert_into, sql_query};
+use diesel::sql_query;
+use diesel::sql_types::{Array, BigInt, Bigint, Integer, Nullable, VarChar};
 use indoc::indoc;
 use serde::{Deserialize, Serialize};
 
+use crate::bot_filter::FilteredVisits;
 use crate::error::WTError;
+use crate::metrics;
 use crate::models::Chapter;
-use crate::schema::{chapters, visits};
+use crate::pages::UnknownPages;
+use crate::readers::Sketch;
+use crate::response_cache::CacheGroup;
+use crate::rollup::{self, DAY_MILLISECONDS, HOUR_MILLISECONDS};
+use crate::schema::{chapters, reader_sketches, site_reader_sketches};
+use crate::visit_buffer::{RecordQuarantinedVisit, RecordVisit};
 use crate::{AppState, DbConnection};
 
 use super::common;
 
-const PAGE_SIZE: i32 = 50;
+/// The length of `TimeFrame::YEAR`, the longest time frame.
+pub const LONGEST_TIME_FRAME_DAYS: u32 = 365;
 
-#[derive(Deserialize, Copy, Clone)]
+#[derive(Deserialize, Copy, Clone, Debug)]
 enum TimeFrame {
     HOUR,
     DAY,
@@ -31,37 +42,68 @@ impl TimeFrame {
             TimeFrame::DAY => 1000 * 3600 * 24,
             TimeFrame::WEEK => 1000 * 3600 * 24 * 7,
             TimeFrame::MONTH => 1000 * 3600 * 24 * 30,
-            TimeFrame::YEAR => 1000 * 3600 * 24 * 365,
+            TimeFrame::YEAR => 1000 * 3600 * 24 * LONGEST_TIME_FRAME_DAYS as i64,
         }
     }
 }
 
-fn count(connection: &DbConnection, relative_path: &str) -> Result<(), WTError> {
-    connection.transaction::<(), WTError, _>(|| {
-        let chapter = common::get_chapter(&connection, &relative_path)?;
-        insert_into(visits::table)
-            .values((
-                visits::chapter_id.eq(chapter.id),
-                visits::timestamp.eq(common::get_current_timestamp()),
-            ))
-            .execute(connection)?;
-        diesel::update(&chapter)
-            .set(chapters::visit_count.eq(chapters::visit_count + 1))
-            .execute(connection)?;
-        Ok(())
-    })
-}
-
+/// Visits are buffered and written in batches by `VisitBuffer`; only paths unknown to the page
+/// registry need the database up front, to find out whether they have a chapter already.
 #[post("/count")]
 async fn count_handler(
     state: web::Data<AppState>,
+    request: HttpRequest,
     content: String,
 ) -> Result<Either<impl Responder, impl Responder>, WTError> {
     if !common::is_page_name(&content) {
         return Ok(Either::Left(HttpResponse::Forbidden()));
     }
-    let connection = state.db_pool.get()?;
-    web::block(move || count(&connection, &content)).await??;
+    let host = state
+        .rate_limiter
+        .client_host(request.peer_addr(), request.headers());
+    if let Some(reason) = state.bot_filter.check(request.headers(), &host) {
+        metrics::VISITS_FILTERED
+            .with_label_values(&[reason.as_str()])
+            .inc();
+        return Ok(match state.bot_filter.action() {
+            FilteredVisits::Reject => Either::Left(HttpResponse::Forbidden()),
+            FilteredVisits::Tally => Either::Right(HttpResponse::Ok().body("<3")),
+        });
+    }
+    let timestamp = common::get_current_timestamp();
+    let registry = state.pages.current();
+    if !registry.is_known(&content) {
+        let connection = state.db_pool.get()?;
+        let relative_path = content.clone();
+        let chapter =
+            common::block(move || common::resolve_chapter(&connection, &relative_path)).await??;
+        if chapter.is_none() {
+            return Ok(match registry.unknown() {
+                UnknownPages::Reject => Either::Left(HttpResponse::Forbidden()),
+                UnknownPages::Quarantine => {
+                    state.visit_buffer.do_send(RecordQuarantinedVisit {
+                        relative_path: content,
+                        timestamp,
+                    });
+                    Either::Right(HttpResponse::Ok().body("<3"))
+                }
+                UnknownPages::Allow => Either::Right(HttpResponse::Ok().body("<3")),
+            });
+        }
+    }
+    let reader = state.reader_salt.as_ref().map(|salt| {
+        let user_agent = request
+            .headers()
+            .get(header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .unwrap_or("");
+        salt.hash(timestamp, &host, user_agent)
+    });
+    state.visit_buffer.do_send(RecordVisit {
+        relative_path: content,
+        timestamp,
+        reader,
+    });
     Ok(Either::Right(HttpResponse::Ok().body("<3")))
 }
 
@@ -81,30 +123,39 @@ struct ChapterAllQuery {
 #[get("/chapters/all")]
 async fn chapter_all_handler(
     state: web::Data<AppState>,
+    request: HttpRequest,
     query: web::Query<ChapterAllQuery>,
 ) -> Result<impl Responder, WTError> {
-    let connection = state.db_pool.get()?;
-    let statement = chapters::table
-        .order(chapters::visit_count.desc())
-        .offset(((query.page - 1) * PAGE_SIZE).into())
-        .limit(PAGE_SIZE.into());
-    let showing_chapters: Vec<Chapter> =
-        web::block(move || statement.load::<Chapter>(&connection)).await??;
-    let chapter_visit_info: ChapterVisitInfo = showing_chapters
-        .into_iter()
-        .map(|showing_chapter| OneChapterVisitInfo {
-            visit_count: showing_chapter.visit_count,
-            relative_path: showing_chapter.relative_path,
-        })
-        .collect();
-    Ok(HttpResponse::Ok().json(chapter_visit_info))
+    let page_size = state.config.analytics.page_size;
+    let key = format!("/stats/chapters/all?page={}", query.page);
+    let load = async {
+        let connection = state.db_pool.get()?;
+        let statement = chapters::table
+            .order(chapters::visit_count.desc())
+            .offset(((query.page - 1) * page_size).into())
+            .limit(page_size.into());
+        let showing_chapters: Vec<Chapter> =
+            common::block(move || statement.load::<Chapter>(&connection)).await??;
+        let chapter_visit_info: ChapterVisitInfo = showing_chapters
+            .into_iter()
+            .map(|showing_chapter| OneChapterVisitInfo {
+                visit_count: showing_chapter.visit_count,
+                relative_path: showing_chapter.relative_path,
+            })
+            .collect();
+        Ok(chapter_visit_info)
+    };
+    state
+        .response_cache
+        .respond(&request, CacheGroup::Chapters, key, load)
+        .await
 }
 
 #[get("/chapters/allRaw")]
 async fn chapter_all_raw_handler(state: web::Data<AppState>) -> Result<impl Responder, WTError> {
     let connection = state.db_pool.get()?;
     let showing_chapters: Vec<Chapter> =
-        web::block(move || chapters::table.load(&connection)).await??;
+        common::block(move || chapters::table.load(&connection)).await??;
     let chapter_visit_info: ChapterVisitInfo = showing_chapters
         .into_iter()
         .map(|showing_chapter| OneChapterVisitInfo {
@@ -115,6 +166,97 @@ async fn chapter_all_raw_handler(state: web::Data<AppState>) -> Result<impl Resp
     Ok(HttpResponse::Ok().json(chapter_visit_info))
 }
 
+#[derive(QueryableByName)]
+struct RecentAggregateResult {
+    #[sql_type = "VarChar"]
+    relative_path: String,
+    #[sql_type = "BigInt"]
+    visit_count: i64,
+}
+
+/// Rows of `chapter_id` and `visit_count` that sum up to the visits per chapter since `cutoff`,
+/// with the parameters `$1` to `$5` bound from `RecentRanges`.
+const RECENT_COUNTS_SQL: &str = indoc! {"
+    SELECT chapter_id, count(1) AS visit_count FROM visits
+        WHERE timestamp >= $1 AND timestamp < $2
+        GROUP BY chapter_id
+    UNION ALL
+    SELECT chapter_id, count(1) AS visit_count FROM visits
+        WHERE timestamp >= $3
+        GROUP BY chapter_id
+    UNION ALL
+    SELECT chapter_id, visit_count FROM visit_rollups_hourly
+        WHERE (bucket_start >= $2 AND bucket_start < $4)
+            OR (bucket_start >= $5 AND bucket_start < $3)
+    UNION ALL
+    SELECT chapter_id, visit_count FROM visit_rollups_daily
+        WHERE bucket_start >= $4 AND bucket_start < $5
+"};
+
+/// Where `RECENT_COUNTS_SQL` switches between raw visits and rollups. Raw visits cover the partial
+/// hour at `cutoff` and whatever has not been rolled up yet, hourly rollups the up to 23 hours
+/// before the first whole day (`$2..$4`) and the hours after the last one (`$5..$3`), and daily
+/// rollups the whole days in between. The partial hour is missed once its raw visits have expired
+/// and the leading hours once retention has removed their hourly rollups.
+struct RecentRanges {
+    cutoff: i64,
+    hourly_start: i64,
+    hourly_end: i64,
+    daily_start: i64,
+    daily_end: i64,
+}
+
+impl RecentRanges {
+    fn load(connection: &DbConnection, cutoff: i64) -> QueryResult<RecentRanges> {
+        let watermarks = rollup::load_watermarks(connection)?;
+        let hourly_start = rollup::ceil(cutoff, HOUR_MILLISECONDS);
+        let hourly_end = hourly_start.max(watermarks.hourly);
+        let daily_start = rollup::ceil(hourly_start, DAY_MILLISECONDS).min(hourly_end);
+        let daily_end =
+            daily_start.max(rollup::floor(hourly_end, DAY_MILLISECONDS).min(watermarks.daily));
+        Ok(RecentRanges {
+            cutoff,
+            hourly_start,
+            hourly_end,
+            daily_start,
+            daily_end,
+        })
+    }
+}
+
+/// Ranks chapters by their visits since `cutoff`.
+fn load_recent(
+    connection: &DbConnection,
+    cutoff: i64,
+    page_size: i32,
+    offset: i32,
+) -> Result<Vec<RecentAggregateResult>, WTError> {
+    let ranges = RecentRanges::load(connection, cutoff)?;
+    let sql = format!(
+        indoc! {"
+            SELECT chapters.relative_path, sum(counts.visit_count)::int8 AS visit_count FROM (
+                {counts}
+            ) AS counts
+                INNER JOIN chapters
+                    ON counts.chapter_id = chapters.id
+                GROUP BY chapters.id
+                ORDER BY visit_count DESC
+                LIMIT $6
+                OFFSET $7
+        "},
+        counts = RECENT_COUNTS_SQL
+    );
+    Ok(sql_query(sql)
+        .bind::<Bigint, i64>(ranges.cutoff)
+        .bind::<Bigint, i64>(ranges.hourly_start)
+        .bind::<Bigint, i64>(ranges.hourly_end)
+        .bind::<Bigint, i64>(ranges.daily_start)
+        .bind::<Bigint, i64>(ranges.daily_end)
+        .bind::<Bigint, i64>(page_size.into())
+        .bind::<Bigint, i64>(offset.into())
+        .get_results(connection)?)
+}
+
 #[derive(Deserialize)]
 struct ChapterRecentQuery {
     page: i32,
@@ -124,41 +266,436 @@ struct ChapterRecentQuery {
 #[get("/chapters/recent")]
 async fn chapter_recent_handler(
     state: web::Data<AppState>,
+    request: HttpRequest,
     query: web::Query<ChapterRecentQuery>,
 ) -> Result<impl Responder, WTError> {
+    let page_size = state.config.analytics.page_size;
+    let key = format!(
+        "/stats/chapters/recent?page={}&time_frame={:?}",
+        query.page, query.time_frame
+    );
+    let load = async {
+        let connection = state.db_pool.get()?;
+        let cutoff = common::get_current_timestamp() - query.time_frame.get_milliseconds();
+        let offset = (query.page - 1) * page_size;
+        let showing_chapters =
+            common::block(move || load_recent(&connection, cutoff, page_size, offset)).await??;
+        let chapter_visit_info: ChapterVisitInfo = showing_chapters
+            .into_iter()
+            .map(|showing_chapter| OneChapterVisitInfo {
+                visit_count: showing_chapter.visit_count,
+                relative_path: showing_chapter.relative_path,
+            })
+            .collect();
+        Ok(chapter_visit_info)
+    };
+    state
+        .response_cache
+        .respond(&request, CacheGroup::Chapters, key, load)
+        .await
+}
+
+#[derive(QueryableByName)]
+struct ChapterCount {
+    #[sql_type = "Integer"]
+    chapter_id: i32,
+    #[sql_type = "BigInt"]
+    visit_count: i64,
+}
+
+#[derive(Serialize)]
+struct FunnelStep {
+    relative_path: String,
+    visit_count: i64,
+    /// Visits relative to those of the first chapter, or null while it has none.
+    retention_from_first: Option<f64>,
+    /// Visits relative to those of the previous chapter, or null for the first chapter and while
+    /// the previous one has none.
+    retention_from_previous: Option<f64>,
+}
+
+fn ratio(visit_count: i64, base: i64) -> Option<f64> {
+    (base > 0).then(|| visit_count as f64 / base as f64)
+}
+
+/// Counts the visits since `cutoff` of every chapter in `relative_paths`, in order. Only aggregate
+/// counts are compared, so the ratios approximate how many readers keep reading rather than
+/// following any reader.
+fn load_funnel(
+    connection: &DbConnection,
+    cutoff: i64,
+    relative_paths: &[String],
+) -> Result<Vec<FunnelStep>, WTError> {
+    let mut chapter_ids = Vec::with_capacity(relative_paths.len());
+    for relative_path in relative_paths {
+        let chapter = common::resolve_chapter(connection, relative_path)?;
+        chapter_ids.push(chapter.map(|chapter| chapter.id));
+    }
+    let known_ids: Vec<i32> = chapter_ids.iter().flatten().copied().collect();
+    let ranges = RecentRanges::load(connection, cutoff)?;
+    let sql = format!(
+        indoc! {"
+            SELECT chapter_id, sum(visit_count)::int8 AS visit_count FROM (
+                {counts}
+            ) AS counts
+                WHERE chapter_id = ANY($6)
+                GROUP BY chapter_id
+        "},
+        counts = RECENT_COUNTS_SQL
+    );
+    let counts: HashMap<i32, i64> = sql_query(sql)
+        .bind::<Bigint, i64>(ranges.cutoff)
+        .bind::<Bigint, i64>(ranges.hourly_start)
+        .bind::<Bigint, i64>(ranges.hourly_end)
+        .bind::<Bigint, i64>(ranges.daily_start)
+        .bind::<Bigint, i64>(ranges.daily_end)
+        .bind::<Array<Integer>, _>(&known_ids)
+        .load::<ChapterCount>(connection)?
+        .into_iter()
+        .map(|count| (count.chapter_id, count.visit_count))
+        .collect();
+    let mut steps: Vec<FunnelStep> = Vec::with_capacity(relative_paths.len());
+    for (relative_path, chapter_id) in relative_paths.iter().zip(chapter_ids) {
+        let visit_count = chapter_id
+            .and_then(|chapter_id| counts.get(&chapter_id).copied())
+            .unwrap_or(0);
+        let first = steps.first().map_or(visit_count, |step| step.visit_count);
+        steps.push(FunnelStep {
+            relative_path: relative_path.clone(),
+            visit_count,
+            retention_from_first: ratio(visit_count, first),
+            retention_from_previous: steps
+                .last()
+                .and_then(|previous| ratio(visit_count, previous.visit_count)),
+        });
+    }
+    Ok(steps)
+}
+
+#[derive(Deserialize)]
+struct FunnelQuery {
+    time_frame: TimeFrame,
+}
+
+/// Visits per chapter of `analytics.funnel_chapters` over the time frame, with retention ratios.
+/// Not found while no funnel is configured.
+#[get("/funnel")]
+async fn funnel_handler(
+    state: web::Data<AppState>,
+    query: web::Query<FunnelQuery>,
+) -> Result<impl Responder, WTError> {
+    if state.funnel_chapters.is_empty() {
+        return Err(WTError::NotFound);
+    }
+    let cutoff = common::get_current_timestamp() - query.time_frame.get_milliseconds();
     let connection = state.db_pool.get()?;
+    let state = state.clone();
+    let steps =
+        common::block(move || load_funnel(&connection, cutoff, &state.funnel_chapters)).await??;
+    Ok(HttpResponse::Ok().json(steps))
+}
+
+/// Number of buckets covered when `from` is omitted.
+const DEFAULT_HISTORY_BUCKETS: i64 = 30;
+
+#[derive(Deserialize, Copy, Clone)]
+#[serde(rename_all = "UPPERCASE")]
+enum Bucket {
+    Hour,
+    Day,
+    Week,
+}
 
+impl Bucket {
+    fn get_milliseconds(self) -> i64 {
+        match self {
+            Bucket::Hour => HOUR_MILLISECONDS,
+            Bucket::Day => DAY_MILLISECONDS,
+            Bucket::Week => DAY_MILLISECONDS * 7,
+        }
+    }
+
+    /// Rounds `timestamp` down to the start of its bucket. Buckets are aligned to UTC midnight,
+    /// weeks to Monday.
+    fn floor(self, timestamp: i64) -> i64 {
+        // 1970-01-01 was a Thursday.
+        let origin = match self {
+            Bucket::Week => DAY_MILLISECONDS * 4,
+            _ => 0,
+        };
+        let width = self.get_milliseconds();
+        origin + (timestamp - origin).div_euclid(width) * width
+    }
+}
+
+/// The buckets of a history request: `count` buckets of `width` milliseconds from `start` to `end`.
+#[derive(Copy, Clone)]
+struct HistoryRange {
+    start: i64,
+    end: i64,
+    width: i64,
+    count: i64,
+}
+
+impl HistoryRange {
+    /// Widens `from..to` to whole buckets. `to` defaults to now and `from` to
+    /// `DEFAULT_HISTORY_BUCKETS` buckets before it.
+    fn new(
+        bucket: Bucket,
+        from: Option<i64>,
+        to: Option<i64>,
+        max_buckets: i64,
+    ) -> Result<HistoryRange, WTError> {
+        let width = bucket.get_milliseconds();
+        let to = to.unwrap_or_else(common::get_current_timestamp);
+        let from = from.unwrap_or_else(|| to.saturating_sub(width * DEFAULT_HISTORY_BUCKETS));
+        if from < 0 || from >= to {
+            return Err(WTError::Validation(
+                "from must not be negative and before to".to_owned(),
+            ));
+        }
+        let start = bucket.floor(from);
+        let count = (bucket.floor(to - 1) - start) / width + 1;
+        if count > max_buckets {
+            return Err(WTError::Validation(format!(
+                "at most {} buckets may be requested",
+                max_buckets
+            )));
+        }
+        let end = start
+            .checked_add(width * count)
+            .ok_or_else(|| WTError::Validation("to is out of range".to_owned()))?;
+        Ok(HistoryRange {
+            start,
+            end,
+            width,
+            count,
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct HistoryPoint {
+    timestamp: i64,
+    visit_count: i64,
+}
+
+/// Counts the visits per bucket of `range`, of one chapter or of all of them. Buckets without
+/// visits are included with a count of zero. Rolled up periods are read from the rollups, so the
+/// history outlives the raw visits.
+fn load_history(
+    connection: &DbConnection,
+    chapter_id: Option<i32>,
+    range: HistoryRange,
+) -> Result<Vec<HistoryPoint>, WTError> {
     #[derive(QueryableByName)]
-    struct RecentAggregateResult {
-        #[sql_type = "VarChar"]
-        relative_path: String,
+    struct BucketResult {
+        #[sql_type = "BigInt"]
+        bucket: i64,
         #[sql_type = "BigInt"]
         visit_count: i64,
     }
+    let watermarks = rollup::load_watermarks(connection)?;
+    // Daily rollups can only fill buckets made of whole days.
+    let daily_end = if range.width % DAY_MILLISECONDS == 0 && range.start % DAY_MILLISECONDS == 0 {
+        range.start.max(watermarks.daily.min(range.end))
+    } else {
+        range.start
+    };
+    let hourly_end = daily_end.max(watermarks.hourly.min(range.end));
     let sql = indoc! {"
-        SELECT chapters.relative_path, count(1) as visit_count FROM visits
-            LEFT JOIN chapters
-                ON visits.chapter_id = chapters.id
-            WHERE visits.timestamp > $1
-            GROUP BY chapters.id
-            ORDER BY visit_count DESC
-            LIMIT $2
-            OFFSET $3
+        SELECT bucket, sum(visit_count)::int8 AS visit_count FROM (
+            SELECT (bucket_start - $1) / $2 AS bucket, visit_count FROM visit_rollups_daily
+                WHERE bucket_start >= $1 AND bucket_start < $3
+                    AND ($6::int4 IS NULL OR chapter_id = $6)
+            UNION ALL
+            SELECT (bucket_start - $1) / $2 AS bucket, visit_count FROM visit_rollups_hourly
+                WHERE bucket_start >= $3 AND bucket_start < $4
+                    AND ($6::int4 IS NULL OR chapter_id = $6)
+            UNION ALL
+            SELECT (timestamp - $1) / $2 AS bucket, count(1) AS visit_count FROM visits
+                WHERE timestamp >= $4 AND timestamp < $5
+                    AND ($6::int4 IS NULL OR chapter_id = $6)
+                GROUP BY 1
+        ) AS counts
+            GROUP BY bucket
     "};
-    let statement = sql_query(sql)
-        .bind::<Bigint, i64>(common::get_current_timestamp() - query.time_frame.get_milliseconds())
-        .bind::<Bigint, i64>(PAGE_SIZE.into())
-        .bind::<Bigint, i64>(((query.page - 1) * PAGE_SIZE).into());
-    let showing_chapters: Vec<RecentAggregateResult> =
-        web::block(move || statement.get_results(&connection)).await??;
-    let chapter_visit_info: ChapterVisitInfo = showing_chapters
-        .into_iter()
-        .map(|showing_chapter| OneChapterVisitInfo {
-            visit_count: showing_chapter.visit_count,
-            relative_path: showing_chapter.relative_path,
+    let buckets: Vec<BucketResult> = sql_query(sql)
+        .bind::<Bigint, i64>(range.start)
+        .bind::<Bigint, i64>(range.width)
+        .bind::<Bigint, i64>(daily_end)
+        .bind::<Bigint, i64>(hourly_end)
+        .bind::<Bigint, i64>(range.end)
+        .bind::<Nullable<Integer>, Option<i32>>(chapter_id)
+        .get_results(connection)?;
+    let mut history: Vec<HistoryPoint> = (0..range.count)
+        .map(|index| HistoryPoint {
+            timestamp: range.start + index * range.width,
+            visit_count: 0,
         })
         .collect();
-    Ok(HttpResponse::Ok().json(chapter_visit_info))
+    for bucket in buckets {
+        history[bucket.bucket as usize].visit_count = bucket.visit_count;
+    }
+    Ok(history)
+}
+
+#[derive(Deserialize)]
+struct ChapterHistoryQuery {
+    relative_path: String,
+    bucket: Bucket,
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+/// Visits of one chapter per hour, day or week between `from` and `to`, both in milliseconds.
+#[get("/chapter/history")]
+async fn chapter_history_handler(
+    state: web::Data<AppState>,
+    query: web::Query<ChapterHistoryQuery>,
+) -> Result<impl Responder, WTError> {
+    let query = query.into_inner();
+    let range = HistoryRange::new(
+        query.bucket,
+        query.from,
+        query.to,
+        state.config.analytics.max_history_buckets,
+    )?;
+    let connection = state.db_pool.get()?;
+    let history = common::block(move || -> Result<Vec<HistoryPoint>, WTError> {
+        let chapter =
+            common::resolve_chapter(&connection, &query.relative_path)?.ok_or(WTError::NotFound)?;
+        load_history(&connection, Some(chapter.id), range)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(history))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    bucket: Bucket,
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+/// Like `/chapter/history`, for the visits of all chapters together.
+#[get("/chapters/history")]
+async fn chapters_history_handler(
+    state: web::Data<AppState>,
+    query: web::Query<HistoryQuery>,
+) -> Result<impl Responder, WTError> {
+    let range = HistoryRange::new(
+        query.bucket,
+        query.from,
+        query.to,
+        state.config.analytics.max_history_buckets,
+    )?;
+    let connection = state.db_pool.get()?;
+    let history = common::block(move || load_history(&connection, None, range)).await??;
+    Ok(HttpResponse::Ok().json(history))
+}
+
+#[derive(Serialize)]
+struct ReadersPoint {
+    timestamp: i64,
+    unique_readers: u64,
+}
+
+/// Estimates the unique readers of each day of `range` from the stored sketches, of one chapter
+/// or of all of them. Readers are hashed with a different salt every day, so days cannot be
+/// combined into a count of unique readers over the whole range.
+fn load_readers(
+    connection: &DbConnection,
+    chapter_id: Option<i32>,
+    range: HistoryRange,
+) -> Result<Vec<ReadersPoint>, WTError> {
+    let sketches: Vec<(i64, Vec<u8>)> = match chapter_id {
+        Some(chapter_id) => reader_sketches::table
+            .filter(reader_sketches::chapter_id.eq(chapter_id))
+            .filter(reader_sketches::day_start.ge(range.start))
+            .filter(reader_sketches::day_start.lt(range.end))
+            .select((reader_sketches::day_start, reader_sketches::registers))
+            .load(connection)?,
+        None => site_reader_sketches::table
+            .filter(site_reader_sketches::day_start.ge(range.start))
+            .filter(site_reader_sketches::day_start.lt(range.end))
+            .select((
+                site_reader_sketches::day_start,
+                site_reader_sketches::registers,
+            ))
+            .load(connection)?,
+    };
+    let mut readers: Vec<ReadersPoint> = (0..range.count)
+        .map(|index| ReadersPoint {
+            timestamp: range.start + index * range.width,
+            unique_readers: 0,
+        })
+        .collect();
+    for (day, registers) in sketches {
+        readers[((day - range.start) / range.width) as usize].unique_readers =
+            Sketch::from_bytes(registers).estimate().round() as u64;
+    }
+    Ok(readers)
+}
+
+#[derive(Deserialize)]
+struct ChapterReadersQuery {
+    relative_path: String,
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+/// Estimated unique readers of one chapter per day between `from` and `to`, both in milliseconds.
+/// Only available when `analytics.unique_readers` is enabled.
+#[get("/chapter/readers")]
+async fn chapter_readers_handler(
+    state: web::Data<AppState>,
+    query: web::Query<ChapterReadersQuery>,
+) -> Result<impl Responder, WTError> {
+    if state.reader_salt.is_none() {
+        return Err(WTError::NotFound);
+    }
+    let query = query.into_inner();
+    let range = HistoryRange::new(
+        Bucket::Day,
+        query.from,
+        query.to,
+        state.config.analytics.max_history_buckets,
+    )?;
+    let connection = state.db_pool.get()?;
+    let readers = common::block(move || -> Result<Vec<ReadersPoint>, WTError> {
+        let chapter =
+            common::resolve_chapter(&connection, &query.relative_path)?.ok_or(WTError::NotFound)?;
+        load_readers(&connection, Some(chapter.id), range)
+    })
+    .await??;
+    Ok(HttpResponse::Ok().json(readers))
+}
+
+#[derive(Deserialize)]
+struct ReadersQuery {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+/// Like `/chapter/readers`, counting a reader of several chapters on the same day once.
+#[get("/chapters/readers")]
+async fn chapters_readers_handler(
+    state: web::Data<AppState>,
+    query: web::Query<ReadersQuery>,
+) -> Result<impl Responder, WTError> {
+    if state.reader_salt.is_none() {
+        return Err(WTError::NotFound);
+    }
+    let range = HistoryRange::new(
+        Bucket::Day,
+        query.from,
+        query.to,
+        state.config.analytics.max_history_buckets,
+    )?;
+    let connection = state.db_pool.get()?;
+    let readers = common::block(move || load_readers(&connection, None, range)).await??;
+    Ok(HttpResponse::Ok().json(readers))
 }
 
 pub fn get_service() -> impl HttpServiceFactory {
@@ -167,4 +704,63 @@ pub fn get_service() -> impl HttpServiceFactory {
         .service(chapter_all_handler)
         .service(chapter_all_raw_handler)
         .service(chapter_recent_handler)
+        .service(funnel_handler)
+        .service(chapter_history_handler)
+        .service(chapters_history_handler)
+        .service(chapter_readers_handler)
+        .service(chapters_readers_handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(bucket: Bucket, from: i64, to: i64, max_buckets: i64) -> HistoryRange {
+        match HistoryRange::new(bucket, Some(from), Some(to), max_buckets) {
+            Ok(range) => range,
+            Err(error) => panic!("{}", error),
+        }
+    }
+
+    #[test]
+    fn history_range_widens_to_whole_buckets() {
+        let hour = HOUR_MILLISECONDS;
+        let range = range(Bucket::Hour, hour + 1, 3 * hour + 1, 10);
+        assert_eq!((range.start, range.end), (hour, 4 * hour));
+        assert_eq!((range.width, range.count), (hour, 3));
+    }
+
+    #[test]
+    fn history_range_end_is_exclusive() {
+        let range = range(Bucket::Day, 0, DAY_MILLISECONDS, 10);
+        assert_eq!(
+            (range.start, range.end, range.count),
+            (0, DAY_MILLISECONDS, 1)
+        );
+    }
+
+    #[test]
+    fn history_range_weeks_start_on_monday() {
+        // 1970-01-05 was a Monday.
+        let monday = 4 * DAY_MILLISECONDS;
+        let range = range(
+            Bucket::Week,
+            monday + DAY_MILLISECONDS,
+            monday + 2 * DAY_MILLISECONDS,
+            10,
+        );
+        assert_eq!(
+            (range.start, range.end),
+            (monday, monday + 7 * DAY_MILLISECONDS)
+        );
+    }
+
+    #[test]
+    fn history_range_rejects_invalid_ranges() {
+        let hour = HOUR_MILLISECONDS;
+        assert!(HistoryRange::new(Bucket::Hour, Some(-1), Some(hour), 10).is_err());
+        assert!(HistoryRange::new(Bucket::Hour, Some(hour), Some(hour), 10).is_err());
+        assert!(HistoryRange::new(Bucket::Hour, Some(0), Some(11 * hour), 10).is_err());
+        assert!(HistoryRange::new(Bucket::Hour, Some(0), Some(10 * hour), 10).is_ok());
+    }
 }
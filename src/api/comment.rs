@@ -1,18 +1,24 @@
 use std::ops::Deref;
 
 use actix_web::dev::HttpServiceFactory;
-use actix_web::{get, post, web, Either, HttpResponse, Responder};
+use actix_web::{get, post, web, Either, HttpRequest, HttpResponse, Responder};
 use diesel::prelude::*;
 use diesel::{insert_into, update};
 use percent_encoding::NON_ALPHANUMERIC;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 
-use crate::api::common::ErrorCode;
+use crate::api::common::{APIResult, ErrorCode};
+use crate::config::CommentConfig;
 use crate::dark_colors::DARK_COLORS;
 use crate::error::WTError;
-use crate::models::{Comment, User};
+use crate::metrics;
+use crate::models::{Comment, CommentRevision, User};
+use crate::pages::PageRegistry;
+use crate::rate_limit::{RateLimitKey, RateLimiter};
+use crate::response_cache::CacheGroup;
 use crate::schema::chapters;
+use crate::schema::comment_revisions;
 use crate::schema::comments;
 use crate::schema::mentions;
 use crate::schema::users;
@@ -20,67 +26,198 @@ use crate::{AppState, DbConnection};
 
 use super::common;
 use super::user;
+use super::user::UserLookup;
 
-pub const MAX_COMMENT_BYTES: usize = 4096;
 pub const MIN_COMMENT_BYTES: usize = 1;
 pub const MAX_MENTIONS_PER_COMMENT: usize = 5;
-const RECENT_COMMENTS_AMOUNT: i64 = 50;
+
+fn validate_content(content: &str, max_comment_bytes: usize) -> Option<ErrorCode> {
+    if content.len() > max_comment_bytes {
+        return Some(ErrorCode::CommentTooLong);
+    }
+    if content.len() < MIN_COMMENT_BYTES {
+        return Some(ErrorCode::CommentTooShort);
+    }
+    None
+}
+
+fn parse_mentions(content: &str) -> Vec<String> {
+    lazy_static! {
+        static ref MENTION_REGEX: Regex = Regex::new("@(\\S+)(?:\\s|$)").unwrap();
+    }
+    let mut mentioned = Vec::new();
+    for (mentions_count, capture) in MENTION_REGEX.captures_iter(content).enumerate() {
+        mentioned.push(capture[1].to_owned());
+        if mentions_count >= MAX_MENTIONS_PER_COMMENT {
+            break;
+        }
+    }
+    mentioned.sort();
+    mentioned.dedup();
+    mentioned
+}
+
+/// Records a mention of every named user and every user in `notified`, skipping those in
+/// `already_mentioned`.
+fn insert_mentions(
+    connection: &DbConnection,
+    comment_id: i64,
+    mentioned: Vec<String>,
+    notified: Vec<i64>,
+    already_mentioned: &[i64],
+    current_timestamp: i64,
+) -> Result<(), WTError> {
+    let mut user_ids = notified;
+    if !mentioned.is_empty() {
+        user_ids.extend(
+            users::table
+                .select(users::id)
+                .filter(users::user_name.eq_any(mentioned))
+                .get_results::<i64>(connection)?,
+        );
+    }
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    user_ids.retain(|user_id| !already_mentioned.contains(user_id));
+    if user_ids.is_empty() {
+        return Ok(());
+    }
+    insert_into(mentions::table)
+        .values(
+            user_ids
+                .into_iter()
+                .map(|user_id| {
+                    (
+                        mentions::from_comment_id.eq(comment_id),
+                        mentions::mentioned_user_id.eq(user_id),
+                        mentions::timestamp.eq(current_timestamp),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .execute(connection)?;
+    Ok(())
+}
+
+/// Picks the comment a reply to `parent_comment_id` should hang under so that no reply is nested
+/// deeper than `max_reply_depth`, together with the author of the comment being replied to.
+/// Returns `None` if the parent does not exist, is deleted or belongs to another chapter.
+fn resolve_parent(
+    connection: &DbConnection,
+    parent_comment_id: i64,
+    chapter_id: i32,
+    max_reply_depth: usize,
+) -> Result<Option<(i64, i64)>, WTError> {
+    let parent: Option<Comment> = comments::table
+        .filter(comments::id.eq(parent_comment_id))
+        .filter(comments::deleted.eq(false))
+        .filter(comments::chapter_id.eq(chapter_id))
+        .first(connection)
+        .optional()?;
+    let parent = match parent {
+        Some(parent) => parent,
+        None => return Ok(None),
+    };
+    // Ancestors of the new reply, nearest first.
+    let mut ancestors = vec![parent.id];
+    let mut next = parent.parent_comment_id;
+    while let Some(ancestor_id) = next {
+        ancestors.push(ancestor_id);
+        next = comments::table
+            .filter(comments::id.eq(ancestor_id))
+            .select(comments::parent_comment_id)
+            .first(connection)?;
+    }
+    let attach_to = reply_target(&ancestors, max_reply_depth);
+    Ok(Some((attach_to, parent.user_id)))
+}
+
+/// Picks the comment a reply hangs under from its ancestors, nearest first: the parent itself, or
+/// the ancestor `max_reply_depth` levels below the top-level comment if the reply would be nested
+/// deeper than that.
+fn reply_target(ancestors: &[i64], max_reply_depth: usize) -> i64 {
+    if ancestors.len() > max_reply_depth {
+        ancestors[ancestors.len() - max_reply_depth]
+    } else {
+        ancestors[0]
+    }
+}
 
 #[derive(Deserialize)]
 struct SendPayload {
     token: String,
     relative_path: String,
     content: String,
+    parent_comment_id: Option<i64>,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn send<TCon: Deref<Target = DbConnection>>(
     connection: TCon,
+    rate_limiter: &RateLimiter,
+    registry: &PageRegistry,
     token: String,
     relative_path: String,
     content: String,
+    parent_comment_id: Option<i64>,
+    max_reply_depth: usize,
     current_timestamp: i64,
     mentioned: Vec<String>,
-) -> Result<bool, WTError> {
-    let user_id = user::get_user_id(&connection, &token)?;
-    if let Some(user_id) = user_id {
-        connection.transaction::<bool, WTError, _>(|| {
-            let chapter = common::get_chapter(&*connection, &relative_path)?;
-            let comment_id: i64 = insert_into(comments::table)
-                .values((
-                    comments::chapter_id.eq(chapter.id),
-                    comments::user_id.eq(user_id),
-                    comments::content.eq(&content),
-                    comments::deleted.eq(false),
-                    comments::create_timestamp.eq(current_timestamp),
-                    comments::update_timestamp.eq(current_timestamp),
-                ))
-                .returning(comments::id)
-                .get_result(&*connection)?;
-            if !mentioned.is_empty() {
-                let user_ids: Vec<i64> = users::table
-                    .select(users::id)
-                    .filter(users::user_name.eq_any(mentioned))
-                    .get_results(&*connection)?;
-                insert_into(mentions::table)
-                    .values(
-                        user_ids
-                            .into_iter()
-                            .map(|user_id| {
-                                (
-                                    mentions::from_comment_id.eq(comment_id),
-                                    mentions::mentioned_user_id.eq(user_id),
-                                    mentions::timestamp.eq(current_timestamp),
-                                )
-                            })
-                            .collect::<Vec<_>>(),
-                    )
-                    .execute(&*connection)?;
+) -> Result<APIResult, WTError> {
+    let user_id = match user::get_user_id(&connection, &token)? {
+        UserLookup::Found(user_id) => user_id,
+        UserLookup::Disabled => return Ok(APIResult::error(ErrorCode::UserDisabled)),
+        UserLookup::NotFound => return Ok(APIResult::forbidden()),
+    };
+    if !rate_limiter.check("/comment/send", RateLimitKey::User(user_id)) {
+        return Ok(APIResult::rate_limited());
+    }
+    let result = connection.transaction::<APIResult, WTError, _>(|| {
+        let chapter = match common::get_chapter(&connection, &relative_path, registry)? {
+            Some(chapter) => chapter,
+            None => return Ok(APIResult::error(ErrorCode::PageUnknown)),
+        };
+        let mut notified = Vec::new();
+        let parent_comment_id = match parent_comment_id {
+            Some(parent_comment_id) => {
+                match resolve_parent(&connection, parent_comment_id, chapter.id, max_reply_depth)? {
+                    Some((attach_to, parent_user_id)) => {
+                        if parent_user_id != user_id {
+                            notified.push(parent_user_id);
+                        }
+                        Some(attach_to)
+                    }
+                    None => return Ok(APIResult::error(ErrorCode::CommentNotFound)),
+                }
             }
-            Ok(true)
-        })
-    } else {
-        Ok(false)
+            None => None,
+        };
+        let comment_id: i64 = insert_into(comments::table)
+            .values((
+                comments::chapter_id.eq(chapter.id),
+                comments::user_id.eq(user_id),
+                comments::content.eq(&content),
+                comments::deleted.eq(false),
+                comments::create_timestamp.eq(current_timestamp),
+                comments::update_timestamp.eq(current_timestamp),
+                comments::parent_comment_id.eq(parent_comment_id),
+            ))
+            .returning(comments::id)
+            .get_result(&*connection)?;
+        insert_mentions(
+            &connection,
+            comment_id,
+            mentioned,
+            notified,
+            &[],
+            current_timestamp,
+        )?;
+        Ok(APIResult::success())
+    })?;
+    if let APIResult::Success(_) = result {
+        metrics::COMMENTS_SENT.inc();
     }
+    Ok(result)
 }
 
 #[post("/send")]
@@ -89,65 +226,205 @@ async fn send_handler(
     payload: web::Json<SendPayload>,
 ) -> Result<impl Responder, WTError> {
     let payload = payload.0;
-    if payload.content.len() > MAX_COMMENT_BYTES {
-        return Ok(Either::Left(common::error_response_with_code(
-            ErrorCode::CommentTooLong,
-        )));
-    }
-    if payload.content.len() < MIN_COMMENT_BYTES {
-        return Ok(Either::Left(common::error_response_with_code(
-            ErrorCode::CommentTooShort,
-        )));
+    if let Some(error_code) =
+        validate_content(&payload.content, state.config.comment.max_comment_bytes)
+    {
+        return Ok(APIResult::error(error_code).into_responder());
     }
     if (!user::is_token(&payload.token)) || (!common::is_page_name(&payload.relative_path)) {
-        return Ok(Either::Right(HttpResponse::Forbidden()));
-    }
-    lazy_static! {
-        static ref MENTION_REGEX: Regex = Regex::new("@(\\S+)(?:\\s|$)").unwrap();
+        return Ok(APIResult::forbidden().into_responder());
     }
     let current_timestamp = common::get_current_timestamp();
-    let mut mentioned = Vec::new();
-    for (mentions_count, capture) in MENTION_REGEX.captures_iter(&payload.content).enumerate() {
-        mentioned.push(capture[1].to_owned());
-        if mentions_count >= MAX_MENTIONS_PER_COMMENT {
-            break;
-        }
-    }
-    mentioned.sort();
-    mentioned.dedup();
+    let mentioned = parse_mentions(&payload.content);
+    let max_reply_depth = state.config.comment.max_reply_depth;
+    let rate_limiter = state.rate_limiter.clone();
+    let registry = state.pages.current();
     let connection = state.db_pool.get()?;
-    if web::block(move || {
+    let result = common::block(move || {
         send(
             connection,
+            &rate_limiter,
+            &registry,
             payload.token,
             payload.relative_path,
             payload.content,
+            payload.parent_comment_id,
+            max_reply_depth,
             current_timestamp,
             mentioned,
         )
     })
-    .await??
+    .await??;
+    if let APIResult::Success(_) = result {
+        state.response_cache.invalidate(CacheGroup::Comments);
+    }
+    Ok(result.into_responder())
+}
+
+#[derive(Deserialize)]
+struct EditPayload {
+    token: String,
+    comment_id: i64,
+    content: String,
+}
+
+fn edit<TCon: Deref<Target = DbConnection>>(
+    connection: TCon,
+    rate_limiter: &RateLimiter,
+    token: String,
+    comment_id: i64,
+    content: String,
+    current_timestamp: i64,
+    mentioned: Vec<String>,
+) -> Result<APIResult, WTError> {
+    let user_id = match user::get_user_id(&connection, &token)? {
+        UserLookup::Found(user_id) => user_id,
+        UserLookup::Disabled => return Ok(APIResult::error(ErrorCode::UserDisabled)),
+        UserLookup::NotFound => return Ok(APIResult::forbidden()),
+    };
+    if !rate_limiter.check("/comment/edit", RateLimitKey::User(user_id)) {
+        return Ok(APIResult::rate_limited());
+    }
+    connection.transaction::<APIResult, WTError, _>(|| {
+        let comment: Option<Comment> = comments::table
+            .filter(comments::id.eq(comment_id))
+            .filter(comments::deleted.eq(false))
+            .filter(comments::user_id.eq(user_id))
+            .for_update()
+            .first(&*connection)
+            .optional()?;
+        let comment = match comment {
+            Some(comment) => comment,
+            None => return Ok(APIResult::forbidden()),
+        };
+        if comment.content == content {
+            return Ok(APIResult::success());
+        }
+        insert_into(comment_revisions::table)
+            .values((
+                comment_revisions::comment_id.eq(comment.id),
+                comment_revisions::content.eq(&comment.content),
+                comment_revisions::timestamp.eq(comment.update_timestamp),
+            ))
+            .execute(&*connection)?;
+        update(&comment)
+            .set((
+                comments::content.eq(&content),
+                comments::update_timestamp.eq(current_timestamp),
+            ))
+            .execute(&*connection)?;
+        let already_mentioned: Vec<i64> = mentions::table
+            .filter(mentions::from_comment_id.eq(comment.id))
+            .select(mentions::mentioned_user_id)
+            .load(&*connection)?;
+        insert_mentions(
+            &connection,
+            comment.id,
+            mentioned,
+            Vec::new(),
+            &already_mentioned,
+            current_timestamp,
+        )?;
+        Ok(APIResult::success())
+    })
+}
+
+#[post("/edit")]
+async fn edit_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<EditPayload>,
+) -> Result<impl Responder, WTError> {
+    let payload = payload.0;
+    if let Some(error_code) =
+        validate_content(&payload.content, state.config.comment.max_comment_bytes)
     {
-        Ok(Either::Left(common::simple_success()))
-    } else {
-        Ok(Either::Right(HttpResponse::Forbidden()))
+        return Ok(APIResult::error(error_code).into_responder());
+    }
+    if !user::is_token(&payload.token) {
+        return Ok(APIResult::forbidden().into_responder());
     }
+    let current_timestamp = common::get_current_timestamp();
+    let mentioned = parse_mentions(&payload.content);
+    let rate_limiter = state.rate_limiter.clone();
+    let connection = state.db_pool.get()?;
+    let result = common::block(move || {
+        edit(
+            connection,
+            &rate_limiter,
+            payload.token,
+            payload.comment_id,
+            payload.content,
+            current_timestamp,
+            mentioned,
+        )
+    })
+    .await??;
+    if let APIResult::Success(_) = result {
+        state.response_cache.invalidate(CacheGroup::Comments);
+    }
+    Ok(result.into_responder())
+}
+
+#[derive(Deserialize)]
+struct GetRevisionsQuery {
+    comment_id: i64,
+}
+
+#[derive(Serialize)]
+struct SingleRevisionResponse {
+    body: String,
+    timestamp: i64,
+}
+
+fn get_revisions<TCon: Deref<Target = DbConnection>>(
+    connection: TCon,
+    comment_id: i64,
+) -> Result<Vec<CommentRevision>, WTError> {
+    let comment: Comment = comments::table
+        .filter(comments::id.eq(comment_id))
+        .filter(comments::deleted.eq(false))
+        .first(&*connection)
+        .optional()?
+        .ok_or(WTError::NotFound)?;
+    Ok(comment_revisions::table
+        .filter(comment_revisions::comment_id.eq(comment.id))
+        .order_by(comment_revisions::id.asc())
+        .load(&*connection)?)
+}
+
+#[get("/getRevisions")]
+async fn get_revisions_handler(
+    state: web::Data<AppState>,
+    query: web::Query<GetRevisionsQuery>,
+) -> Result<impl Responder, WTError> {
+    let connection = state.db_pool.get()?;
+    let revisions = common::block(move || get_revisions(connection, query.comment_id)).await??;
+    Ok(HttpResponse::Ok().json(
+        revisions
+            .into_iter()
+            .map(|revision| SingleRevisionResponse {
+                body: revision.content,
+                timestamp: revision.timestamp,
+            })
+            .collect::<Vec<_>>(),
+    ))
 }
 
 #[derive(Serialize)]
-struct SingleUserResponse {
+pub struct SingleUserResponse {
     avatar_url: String,
     user_name: String,
     display_name: String,
 }
 
 #[derive(Serialize)]
-struct SingleCommentResponse {
+pub struct SingleCommentResponse {
     body: String,
     create_timestamp: i64,
     update_timestamp: i64,
     relative_path: String,
     id: i64,
+    parent_comment_id: Option<i64>,
     user: SingleUserResponse,
 }
 
@@ -170,15 +447,15 @@ fn get_user_avatar_url(user: &User) -> String {
 }
 
 #[derive(Queryable)]
-struct SingleCommentQueryResult {
-    relative_path: String,
-    comment: Comment,
-    user: User,
+pub struct SingleCommentQueryResult {
+    pub relative_path: String,
+    pub comment: Comment,
+    pub user: User,
 }
 
 type CommentQueryResults = Vec<SingleCommentQueryResult>;
 
-fn convert_comment_query_results_to_response(
+pub fn convert_comment_query_results_to_response(
     comment_query_result: CommentQueryResults,
 ) -> Vec<SingleCommentResponse> {
     comment_query_result
@@ -194,6 +471,7 @@ fn convert_comment_query_results_to_response(
                 update_timestamp: comment.update_timestamp,
                 relative_path,
                 id: comment.id,
+                parent_comment_id: comment.parent_comment_id,
                 user: SingleUserResponse {
                     avatar_url: get_user_avatar_url(&user),
                     user_name: user.user_name,
@@ -204,47 +482,184 @@ fn convert_comment_query_results_to_response(
         .collect()
 }
 
+/// A keyset page: comments with an id below `before_id`, newest first.
+struct Page {
+    before_id: Option<i64>,
+    limit: i64,
+}
+
+impl Page {
+    /// Returns `None` for callers passing neither `before_id` nor `limit`, who keep receiving a
+    /// bare array instead of a `CommentPageResponse`.
+    fn from_query(
+        before_id: Option<i64>,
+        limit: Option<i64>,
+        config: &CommentConfig,
+    ) -> Option<Page> {
+        if before_id.is_none() && limit.is_none() {
+            return None;
+        }
+        Some(Page {
+            before_id,
+            limit: limit
+                .unwrap_or(config.recent_comments_amount)
+                .clamp(1, config.max_page_size),
+        })
+    }
+
+    /// One extra row is fetched to find out whether another page exists.
+    fn fetch_limit(&self) -> i64 {
+        self.limit + 1
+    }
+
+    /// Drops the extra row fetched by `fetch_limit` and returns the `before_id` of the next page,
+    /// or `None` on the last page.
+    fn next_cursor(&self, results: &mut CommentQueryResults) -> Option<i64> {
+        if results.len() as i64 > self.limit {
+            results.truncate(self.limit as usize);
+            results.last().map(|result| result.comment.id)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CommentPageResponse {
+    comments: Vec<SingleCommentResponse>,
+    next_cursor: Option<i64>,
+}
+
+/// A `CommentPageResponse` for paginated requests, or a bare array for the others.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum CommentsBody {
+    Page(CommentPageResponse),
+    All(Vec<SingleCommentResponse>),
+}
+
+fn comments_body(mut results: CommentQueryResults, page: Option<Page>) -> CommentsBody {
+    match page {
+        Some(page) => {
+            let next_cursor = page.next_cursor(&mut results);
+            CommentsBody::Page(CommentPageResponse {
+                comments: convert_comment_query_results_to_response(results),
+                next_cursor,
+            })
+        }
+        None => CommentsBody::All(convert_comment_query_results_to_response(results)),
+    }
+}
+
+fn comments_response(results: CommentQueryResults, page: Option<Page>) -> HttpResponse {
+    HttpResponse::Ok().json(comments_body(results, page))
+}
+
 #[derive(Deserialize)]
 struct GetChapterQuery {
     relative_path: String,
+    before_id: Option<i64>,
+    limit: Option<i64>,
 }
 
 fn get_chapter<TCon: Deref<Target = DbConnection>>(
     connection: TCon,
     relative_path: String,
+    before_id: Option<i64>,
+    limit: Option<i64>,
 ) -> Result<CommentQueryResults, WTError> {
-    Ok(chapters::table
+    let chapter = match common::resolve_chapter(&connection, &relative_path)? {
+        Some(chapter) => chapter,
+        None => return Ok(Vec::new()),
+    };
+    let mut statement = chapters::table
         .inner_join(comments::table.inner_join(users::table))
         .select((
             chapters::relative_path,
             comments::table::all_columns(),
             users::table::all_columns(),
         ))
-        .filter(chapters::relative_path.eq(&relative_path))
+        .filter(chapters::id.eq(chapter.id))
         .filter(comments::deleted.eq(false))
         .order_by(comments::id.desc())
-        .load(&*connection)?)
+        .into_boxed();
+    if let Some(before_id) = before_id {
+        statement = statement.filter(comments::id.lt(before_id));
+    }
+    if let Some(limit) = limit {
+        statement = statement.limit(limit);
+    }
+    Ok(statement.load(&*connection)?)
 }
 
 #[get("/getChapter")]
 async fn get_chapter_handler(
     state: web::Data<AppState>,
     query: web::Query<GetChapterQuery>,
+) -> Result<Either<impl Responder, impl Responder>, WTError> {
+    if !common::is_page_name(&query.relative_path) {
+        return Ok(Either::Right(HttpResponse::Forbidden()));
+    }
+    let query = query.0;
+    let page = Page::from_query(query.before_id, query.limit, &state.config.comment);
+    let before_id = page.as_ref().and_then(|page| page.before_id);
+    let limit = page.as_ref().map(Page::fetch_limit);
+    let connection = state.db_pool.get()?;
+    let results =
+        common::block(move || get_chapter(connection, query.relative_path, before_id, limit))
+            .await??;
+    Ok(Either::Left(comments_response(results, page)))
+}
+
+#[derive(Deserialize)]
+struct ChapterCountQuery {
+    relative_path: String,
+}
+
+#[derive(Serialize)]
+struct ChapterCountResponse {
+    count: i64,
+}
+
+fn count_chapter(connection: &DbConnection, relative_path: &str) -> Result<i64, WTError> {
+    let chapter = match common::resolve_chapter(connection, relative_path)? {
+        Some(chapter) => chapter,
+        None => return Ok(0),
+    };
+    Ok(comments::table
+        .filter(comments::chapter_id.eq(chapter.id))
+        .filter(comments::deleted.eq(false))
+        .count()
+        .get_result(connection)?)
+}
+
+#[get("/getChapterCount")]
+async fn get_chapter_count_handler(
+    state: web::Data<AppState>,
+    query: web::Query<ChapterCountQuery>,
 ) -> Result<Either<impl Responder, impl Responder>, WTError> {
     if !common::is_page_name(&query.relative_path) {
         return Ok(Either::Right(HttpResponse::Forbidden()));
     }
     let connection = state.db_pool.get()?;
-    let results = web::block(move || get_chapter(connection, query.0.relative_path)).await??;
+    let count = common::block(move || count_chapter(&connection, &query.relative_path)).await??;
     Ok(Either::Left(
-        HttpResponse::Ok().json(convert_comment_query_results_to_response(results)),
+        HttpResponse::Ok().json(ChapterCountResponse { count }),
     ))
 }
 
+#[derive(Deserialize)]
+struct GetRecentQuery {
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
 fn get_recent<TCon: Deref<Target = DbConnection>>(
     connection: TCon,
+    before_id: Option<i64>,
+    limit: i64,
 ) -> Result<CommentQueryResults, WTError> {
-    Ok(comments::table
+    let mut statement = comments::table
         .inner_join(users::table)
         .inner_join(chapters::table)
         .select((
@@ -254,35 +669,60 @@ fn get_recent<TCon: Deref<Target = DbConnection>>(
         ))
         .filter(comments::deleted.eq(false))
         .order_by(comments::id.desc())
-        .limit(RECENT_COMMENTS_AMOUNT)
-        .load(&*connection)?)
+        .limit(limit)
+        .into_boxed();
+    if let Some(before_id) = before_id {
+        statement = statement.filter(comments::id.lt(before_id));
+    }
+    Ok(statement.load(&*connection)?)
 }
 
 #[get("/getRecent")]
 async fn get_recent_comments_handler(
     state: web::Data<AppState>,
+    request: HttpRequest,
+    query: web::Query<GetRecentQuery>,
 ) -> Result<impl Responder, WTError> {
-    let connection = state.db_pool.get()?;
-    let results = web::block(move || get_recent(connection)).await??;
-    Ok(HttpResponse::Ok().json(convert_comment_query_results_to_response(results)))
+    let page = Page::from_query(query.before_id, query.limit, &state.config.comment);
+    let before_id = page.as_ref().and_then(|page| page.before_id);
+    let limit = page.as_ref().map_or(
+        state.config.comment.recent_comments_amount,
+        Page::fetch_limit,
+    );
+    let key = match &page {
+        Some(page) => format!(
+            "/comment/getRecent?before_id={:?}&limit={}",
+            page.before_id, page.limit
+        ),
+        None => "/comment/getRecent".to_owned(),
+    };
+    let load = async {
+        let connection = state.db_pool.get()?;
+        let results = common::block(move || get_recent(connection, before_id, limit)).await??;
+        Ok(comments_body(results, page))
+    };
+    state
+        .response_cache
+        .respond(&request, CacheGroup::Comments, key, load)
+        .await
 }
 
 #[derive(Deserialize)]
 struct GetRecentMentionedPayload {
     token: String,
+    before_id: Option<i64>,
+    limit: Option<i64>,
 }
 
 fn get_recent_mentioned<TCon: Deref<Target = DbConnection>>(
     connection: TCon,
     token: String,
-    current_timestamp: i64,
+    before_id: Option<i64>,
+    limit: i64,
 ) -> Result<CommentQueryResults, WTError> {
-    let user = user::get_user(&connection, &token)?;
-    if let Some(user) = user {
-        diesel::update(&user)
-            .set(users::last_checked_mentions_timestamp.eq(current_timestamp))
-            .execute(&*connection)?;
-        Ok(mentions::table
+    let user_id = user::get_user_id(&connection, &token)?.found();
+    if let Some(user_id) = user_id {
+        let mut statement = mentions::table
             .inner_join(
                 comments::table
                     .inner_join(chapters::table)
@@ -294,10 +734,14 @@ fn get_recent_mentioned<TCon: Deref<Target = DbConnection>>(
                 users::table::all_columns(),
             ))
             .filter(comments::deleted.eq(false))
-            .filter(mentions::mentioned_user_id.eq(user.id))
+            .filter(mentions::mentioned_user_id.eq(user_id))
             .order_by(comments::id.desc())
-            .limit(RECENT_COMMENTS_AMOUNT)
-            .load(&*connection)?)
+            .limit(limit)
+            .into_boxed();
+        if let Some(before_id) = before_id {
+            statement = statement.filter(comments::id.lt(before_id));
+        }
+        Ok(statement.load(&*connection)?)
     } else {
         Ok(vec![])
     }
@@ -311,14 +755,18 @@ async fn get_recent_mentioned_comments_handler(
     if !user::is_token(&payload.token) {
         return Ok(Either::Right(HttpResponse::Forbidden()));
     }
+    let payload = payload.0;
+    let page = Page::from_query(payload.before_id, payload.limit, &state.config.comment);
+    let before_id = page.as_ref().and_then(|page| page.before_id);
+    let limit = page.as_ref().map_or(
+        state.config.comment.recent_comments_amount,
+        Page::fetch_limit,
+    );
     let connection = state.db_pool.get()?;
-    let current_timestamp = common::get_current_timestamp();
     let results =
-        web::block(move || get_recent_mentioned(connection, payload.0.token, current_timestamp))
+        common::block(move || get_recent_mentioned(connection, payload.token, before_id, limit))
             .await??;
-    Ok(Either::Left(
-        HttpResponse::Ok().json(convert_comment_query_results_to_response(results)),
-    ))
+    Ok(Either::Left(comments_response(results, page)))
 }
 
 #[derive(Deserialize)]
@@ -331,20 +779,26 @@ fn delete<TCon: Deref<Target = DbConnection>>(
     connection: TCon,
     comment_id: i64,
     token: String,
-) -> Result<bool, WTError> {
+) -> Result<APIResult, WTError> {
     // Diesel does not support update/deleted with joined table
     // https://github.com/diesel-rs/diesel/issues/1478
-    let user_id = user::get_user_id(&connection, &token)?;
-    if let Some(user_id) = user_id {
-        let affected = update(comments::table)
-            .filter(comments::id.eq(comment_id))
-            .filter(comments::deleted.eq(false))
-            .filter(comments::user_id.eq(user_id))
-            .set(comments::deleted.eq(true))
-            .execute(&*connection)?;
-        Ok(affected == 1)
-    } else {
-        Ok(true)
+    match user::get_user_id(&connection, &token)? {
+        UserLookup::Found(user_id) => {
+            let affected = update(comments::table)
+                .filter(comments::id.eq(comment_id))
+                .filter(comments::deleted.eq(false))
+                .filter(comments::user_id.eq(user_id))
+                .set(comments::deleted.eq(true))
+                .execute(&*connection)?;
+            Ok(if affected == 1 {
+                metrics::COMMENTS_DELETED.inc();
+                APIResult::success()
+            } else {
+                APIResult::forbidden()
+            })
+        }
+        UserLookup::Disabled => Ok(APIResult::error(ErrorCode::UserDisabled)),
+        UserLookup::NotFound => Ok(APIResult::success()),
     }
 }
 
@@ -357,18 +811,138 @@ async fn delete_handler(
         return Ok(Either::Right(HttpResponse::Forbidden()));
     }
     let connection = state.db_pool.get()?;
-    if web::block(move || delete(connection, payload.comment_id, payload.0.token)).await?? {
-        Ok(Either::Left(common::simple_success()))
-    } else {
-        Ok(Either::Right(HttpResponse::Forbidden()))
+    let result =
+        common::block(move || delete(connection, payload.comment_id, payload.0.token)).await??;
+    if let APIResult::Success(_) = result {
+        state.response_cache.invalidate(CacheGroup::Comments);
     }
+    Ok(Either::Left(result.into_responder()))
 }
 
 pub fn get_service() -> impl HttpServiceFactory {
     web::scope("/comment")
         .service(send_handler)
         .service(get_chapter_handler)
+        .service(get_chapter_count_handler)
         .service(get_recent_comments_handler)
         .service(get_recent_mentioned_comments_handler)
         .service(delete_handler)
+        .service(edit_handler)
+        .service(get_revisions_handler)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CommentConfig {
+        CommentConfig {
+            recent_comments_amount: 20,
+            max_page_size: 50,
+            ..CommentConfig::default()
+        }
+    }
+
+    /// Query results for comments with the given ids, newest first.
+    fn results(ids: &[i64]) -> CommentQueryResults {
+        ids.iter()
+            .map(|&id| SingleCommentQueryResult {
+                relative_path: "test/page".to_owned(),
+                comment: Comment {
+                    id,
+                    chapter_id: 1,
+                    user_id: 1,
+                    content: format!("comment {}", id),
+                    deleted: false,
+                    create_timestamp: id,
+                    update_timestamp: id,
+                    parent_comment_id: None,
+                },
+                user: User {
+                    id: 1,
+                    token: String::new(),
+                    email: None,
+                    user_name: "test".to_owned(),
+                    display_name: "test".to_owned(),
+                    disabled: false,
+                    last_checked_mentions_timestamp: 0,
+                    role: 0,
+                },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replies_within_the_depth_hang_under_their_parent() {
+        assert_eq!(reply_target(&[1], 3), 1);
+        assert_eq!(reply_target(&[3, 2, 1], 3), 3);
+    }
+
+    #[test]
+    fn deeper_replies_hang_under_the_deepest_allowed_ancestor() {
+        // The parent 4 replies to 3, which replies to 2, which replies to the top-level comment 1.
+        assert_eq!(reply_target(&[4, 3, 2, 1], 3), 3);
+        assert_eq!(reply_target(&[5, 4, 3, 2, 1], 3), 3);
+        assert_eq!(reply_target(&[5, 4, 3, 2, 1], 1), 1);
+    }
+
+    #[test]
+    fn page_is_only_used_when_before_id_or_limit_is_given() {
+        assert!(Page::from_query(None, None, &config()).is_none());
+        let page = Page::from_query(Some(100), None, &config()).unwrap();
+        assert_eq!((page.before_id, page.limit), (Some(100), 20));
+        let page = Page::from_query(None, Some(5), &config()).unwrap();
+        assert_eq!((page.before_id, page.limit), (None, 5));
+    }
+
+    #[test]
+    fn page_limit_is_clamped() {
+        assert_eq!(Page::from_query(None, Some(0), &config()).unwrap().limit, 1);
+        assert_eq!(
+            Page::from_query(None, Some(1000), &config()).unwrap().limit,
+            50
+        );
+        let page = Page::from_query(None, Some(-3), &config()).unwrap();
+        assert_eq!((page.limit, page.fetch_limit()), (1, 2));
+    }
+
+    #[test]
+    fn next_cursor_points_below_the_last_returned_comment() {
+        let page = Page::from_query(None, Some(2), &config()).unwrap();
+        let mut results = results(&[9, 7, 4]);
+        assert_eq!(page.next_cursor(&mut results), Some(7));
+        let ids: Vec<i64> = results.iter().map(|result| result.comment.id).collect();
+        assert_eq!(ids, vec![9, 7]);
+    }
+
+    #[test]
+    fn next_cursor_is_none_on_the_last_page() {
+        let page = Page::from_query(Some(9), Some(2), &config()).unwrap();
+        let mut results = results(&[7, 4]);
+        assert_eq!(page.next_cursor(&mut results), None);
+        assert_eq!(results.len(), 2);
+    }
+
+    async fn response_body(response: HttpResponse) -> String {
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn unpaginated_requests_receive_a_bare_array() {
+        let body = response_body(comments_response(results(&[9, 7]), None)).await;
+        assert!(body.starts_with("[{"), "{}", body);
+        assert!(!body.contains("next_cursor"), "{}", body);
+    }
+
+    #[actix_rt::test]
+    async fn paginated_requests_receive_comments_and_a_cursor() {
+        let page = Page::from_query(None, Some(1), &config());
+        let body = response_body(comments_response(results(&[9, 7]), page)).await;
+        assert!(body.starts_with("{\"comments\":[{"), "{}", body);
+        assert!(body.ends_with("\"next_cursor\":9}"), "{}", body);
+        assert!(!body.contains("\"id\":7"), "{}", body);
+    }
 }
@@ -1,7 +1,10 @@
 use std::convert::TryInto;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::future::Future;
+use std::panic::Location;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use actix_web::{Either, HttpResponse, Responder};
+use actix_web::error::BlockingError;
+use actix_web::{web, Either, HttpResponse, Responder, ResponseError};
 use diesel::insert_into;
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
@@ -9,7 +12,12 @@ use diesel::result::Error;
 use serde::Serialize;
 use serde_repr::{Deserialize_repr, Serialize_repr};
 
+use crate::error::WTError;
+use crate::metrics;
 use crate::models::Chapter;
+use crate::pages::PageRegistry;
+use crate::readers;
+use crate::rollup;
 
 pub const MAX_PAGE_NAME_BYTES: usize = 1024;
 pub const MIN_PAGE_NAME_BYTES: usize = 1;
@@ -27,24 +35,153 @@ pub enum ErrorCode {
     NameTooShort = 8,
     CommentTooShort = 9,
     NameInvalid = 10,
+    UserDisabled = 11,
+    CommentNotFound = 12,
+    UserNotFound = 13,
+    RateLimited = 14,
+    InternalError = 15,
+    NotFound = 16,
+    Forbidden = 17,
+    ValidationFailed = 18,
+    DatabaseUnavailable = 19,
+    PoolTimeout = 20,
+    ChapterNotFound = 21,
+    PageUnknown = 22,
 }
 
-pub fn get_chapter(connection: &PgConnection, relative_path_value: &str) -> Result<Chapter, Error> {
-    use crate::schema::chapters::dsl::*;
-    let chapter: Option<Chapter> = chapters
-        .filter(relative_path.eq(relative_path_value))
+/// Looks up the chapter at `relative_path`, either directly or through one of its aliases.
+pub fn resolve_chapter(
+    connection: &PgConnection,
+    relative_path: &str,
+) -> Result<Option<Chapter>, Error> {
+    use crate::schema::{chapter_aliases, chapters};
+    let chapter: Option<Chapter> = chapters::table
+        .filter(chapters::relative_path.eq(relative_path))
         .first(connection)
         .optional()?;
-    if let Some(chapter) = chapter {
-        Ok(chapter)
+    if chapter.is_some() {
+        return Ok(chapter);
+    }
+    chapter_aliases::table
+        .inner_join(chapters::table)
+        .filter(chapter_aliases::relative_path.eq(relative_path))
+        .select(chapters::all_columns)
+        .first(connection)
+        .optional()
+}
+
+/// Resolves the chapter at `relative_path_value`, creating it if the page registry knows the path.
+pub fn get_chapter(
+    connection: &PgConnection,
+    relative_path_value: &str,
+    registry: &PageRegistry,
+) -> Result<Option<Chapter>, Error> {
+    if let Some(chapter) = resolve_chapter(connection, relative_path_value)? {
+        Ok(Some(chapter))
+    } else if registry.is_known(relative_path_value) {
+        Ok(Some(create_chapter(connection, relative_path_value)?))
     } else {
-        let row: Chapter = insert_into(chapters)
-            .values(relative_path.eq(relative_path_value))
-            .get_result(connection)?;
-        Ok(row)
+        Ok(None)
     }
 }
 
+/// Inserts a chapter at `relative_path_value`, returning the existing one if another request got
+/// there first.
+pub fn create_chapter(
+    connection: &PgConnection,
+    relative_path_value: &str,
+) -> Result<Chapter, Error> {
+    use crate::schema::chapters::dsl::*;
+    let row: Option<Chapter> = insert_into(chapters)
+        .values(relative_path.eq(relative_path_value))
+        .on_conflict_do_nothing()
+        .get_result(connection)
+        .optional()?;
+    match row {
+        Some(row) => Ok(row),
+        None => chapters
+            .filter(relative_path.eq(relative_path_value))
+            .first(connection),
+    }
+}
+
+/// Makes `relative_path` resolve to `chapter`, replacing any alias it had. The caller has to make
+/// sure no chapter exists at `relative_path` itself.
+pub fn alias_chapter(
+    connection: &PgConnection,
+    relative_path: &str,
+    chapter: &Chapter,
+) -> Result<(), Error> {
+    use crate::schema::chapter_aliases;
+    insert_into(chapter_aliases::table)
+        .values((
+            chapter_aliases::relative_path.eq(relative_path),
+            chapter_aliases::chapter_id.eq(chapter.id),
+        ))
+        .on_conflict(chapter_aliases::relative_path)
+        .do_update()
+        .set(chapter_aliases::chapter_id.eq(chapter.id))
+        .execute(connection)?;
+    Ok(())
+}
+
+pub struct MergeCounts {
+    pub visits: usize,
+    pub comments: usize,
+}
+
+/// Moves the visits, rollups, reader sketches and comments of `from` onto `into`, deletes `from`
+/// and keeps its path and aliases resolving to `into`. Must be run inside a transaction.
+pub fn merge_chapters(
+    connection: &PgConnection,
+    from: &Chapter,
+    into: &Chapter,
+) -> Result<MergeCounts, Error> {
+    use crate::schema::{chapter_aliases, chapters, comments, visits, visits_archive};
+    let visits = diesel::update(visits::table.filter(visits::chapter_id.eq(from.id)))
+        .set(visits::chapter_id.eq(into.id))
+        .execute(connection)?;
+    diesel::update(visits_archive::table.filter(visits_archive::chapter_id.eq(from.id)))
+        .set(visits_archive::chapter_id.eq(into.id))
+        .execute(connection)?;
+    let comments = diesel::update(comments::table.filter(comments::chapter_id.eq(from.id)))
+        .set(comments::chapter_id.eq(into.id))
+        .execute(connection)?;
+    diesel::update(chapter_aliases::table.filter(chapter_aliases::chapter_id.eq(from.id)))
+        .set(chapter_aliases::chapter_id.eq(into.id))
+        .execute(connection)?;
+    rollup::move_rollups(connection, from.id, into.id)?;
+    readers::move_sketches(connection, from.id, into.id)?;
+    diesel::update(into)
+        .set(chapters::visit_count.eq(chapters::visit_count + from.visit_count))
+        .execute(connection)?;
+    diesel::delete(from).execute(connection)?;
+    alias_chapter(connection, &from.relative_path, into)?;
+    Ok(MergeCounts { visits, comments })
+}
+
+/// Runs blocking database work on the thread pool like `web::block`, inside a span named after the
+/// calling location so that slow queries can be traced back to their handler.
+#[track_caller]
+pub fn block<F, R>(f: F) -> impl Future<Output = Result<R, BlockingError>>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let location = Location::caller();
+    let span = tracing::debug_span!("db", location = %location);
+    web::block(move || {
+        span.in_scope(|| {
+            let start = Instant::now();
+            let result = f();
+            let elapsed = start.elapsed().as_secs_f64();
+            metrics::observe_db_call(&location.to_string(), elapsed);
+            tracing::debug!(elapsed_ms = elapsed * 1000.0, "database call finished");
+            result
+        })
+    })
+}
+
 pub fn get_current_timestamp() -> i64 {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -58,15 +195,6 @@ pub fn is_page_name(page_name: &str) -> bool {
     page_name.len() <= MAX_PAGE_NAME_BYTES && page_name.len() >= MIN_PAGE_NAME_BYTES
 }
 
-#[derive(Serialize)]
-struct SimpleSuccessResponse {
-    success: bool,
-}
-
-pub fn simple_success() -> HttpResponse {
-    HttpResponse::Ok().json(SimpleSuccessResponse { success: true })
-}
-
 #[derive(Serialize)]
 struct ErrorResponse {
     success: bool,
@@ -97,6 +225,7 @@ pub enum APIResult<T: Serialize = Empty> {
     Success(T),
     Error(ErrorCode),
     Forbidden,
+    RateLimited,
 }
 
 impl<T: Serialize> APIResult<T> {
@@ -109,6 +238,9 @@ impl<T: Serialize> APIResult<T> {
     pub fn forbidden() -> Self {
         APIResult::Forbidden
     }
+    pub fn rate_limited() -> Self {
+        APIResult::RateLimited
+    }
     pub fn into_responder(self) -> impl Responder {
         match self {
             APIResult::Success(value) => {
@@ -135,7 +267,8 @@ impl<T: Serialize> APIResult<T> {
                     code,
                 }))
             }
-            APIResult::Forbidden => Either::Right(HttpResponse::Forbidden()),
+            APIResult::Forbidden => Either::Right(WTError::Forbidden.error_response()),
+            APIResult::RateLimited => Either::Right(WTError::RateLimited.error_response()),
         }
     }
 }
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::{get, web, HttpResponse, Responder};
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::sql_query;
+use diesel::RunQueryDsl;
+use serde::Serialize;
+
+use crate::api::common;
+use crate::error::WTError;
+use crate::{pending_migrations, AppState, DbConnection};
+
+// Probes should fail fast instead of waiting out the pool's regular connection timeout.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize)]
+struct LivenessResponse {
+    status: &'static str,
+}
+
+/// Only tells that the process is able to serve requests; never touches the database.
+#[get("/healthz")]
+async fn healthz_handler() -> impl Responder {
+    HttpResponse::Ok().json(LivenessResponse { status: "ok" })
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    ok: bool,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ReadinessResponse {
+    ready: bool,
+    checks: BTreeMap<&'static str, CheckResult>,
+}
+
+/// Runs `check`, recording its outcome under `name`. Failure details are logged rather than
+/// returned, since the endpoint is reachable by anyone.
+fn run_check<T>(
+    checks: &mut BTreeMap<&'static str, CheckResult>,
+    name: &'static str,
+    check: impl FnOnce() -> Result<T, String>,
+) -> Option<T> {
+    let start = Instant::now();
+    let result = check();
+    let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+    let (value, error) = match result {
+        Ok(value) => (Some(value), None),
+        Err(error) => {
+            tracing::warn!(check = name, error = %error, "readiness check failed");
+            (None, Some(format!("{} check failed", name)))
+        }
+    };
+    checks.insert(
+        name,
+        CheckResult {
+            ok: value.is_some(),
+            latency_ms,
+            error,
+        },
+    );
+    value
+}
+
+fn check_readiness(
+    db_pool: Pool<ConnectionManager<DbConnection>>,
+) -> BTreeMap<&'static str, CheckResult> {
+    let mut checks = BTreeMap::new();
+    let connection = run_check(&mut checks, "pool", || {
+        db_pool
+            .get_timeout(CONNECTION_TIMEOUT)
+            .map_err(|error| error.to_string())
+    });
+    if let Some(connection) = connection {
+        run_check(&mut checks, "query", || {
+            sql_query("SELECT 1")
+                .execute(&*connection)
+                .map_err(|error| error.to_string())
+        });
+        run_check(&mut checks, "migrations", || {
+            let pending = pending_migrations(&connection).map_err(|error| error.to_string())?;
+            if pending.is_empty() {
+                Ok(())
+            } else {
+                Err(format!("{} migrations pending", pending.len()))
+            }
+        });
+    }
+    checks
+}
+
+/// Ready once a connection can be checked out, a trivial query succeeds and every embedded
+/// migration has been applied. Responds with 503 otherwise.
+#[get("/readyz")]
+async fn readyz_handler(state: web::Data<AppState>) -> Result<impl Responder, WTError> {
+    let db_pool = state.db_pool.clone();
+    let checks = common::block(move || check_readiness(db_pool)).await?;
+    let ready = checks.values().all(|check| check.ok);
+    let mut response = if ready {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::ServiceUnavailable()
+    };
+    Ok(response.json(ReadinessResponse { ready, checks }))
+}
+
+pub fn get_service() -> impl HttpServiceFactory {
+    (healthz_handler, readyz_handler)
+}
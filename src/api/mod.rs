@@ -3,3 +3,6 @@ pub mod analytics;
 pub mod comment;
 pub mod user;
 pub mod event;
+pub mod admin;
+pub mod notification;
+pub mod health;
@@ -0,0 +1,229 @@
+use std::ops::Deref;
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::{post, web, Either, HttpResponse, Responder};
+use diesel::prelude::*;
+use diesel::update;
+use serde::{Deserialize, Serialize};
+
+use crate::api::comment::{
+    convert_comment_query_results_to_response, SingleCommentQueryResult, SingleCommentResponse,
+};
+use crate::api::common::{APIResult, ErrorCode};
+use crate::api::user::UserLookup;
+use crate::api::{common, user};
+use crate::error::WTError;
+use crate::models::{Comment, Mention, User};
+use crate::schema::{chapters, comments, mentions, users};
+use crate::{AppState, DbConnection};
+
+pub fn count_unread(connection: &DbConnection, user_id: i64) -> QueryResult<i64> {
+    mentions::table
+        .inner_join(comments::table)
+        .filter(mentions::mentioned_user_id.eq(user_id))
+        .filter(mentions::read.eq(false))
+        .filter(comments::deleted.eq(false))
+        .count()
+        .get_result(connection)
+}
+
+#[derive(Deserialize)]
+struct ListPayload {
+    token: String,
+    #[serde(default)]
+    unread_only: bool,
+    before_id: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct SingleNotificationResponse {
+    id: i64,
+    read: bool,
+    timestamp: i64,
+    comment: SingleCommentResponse,
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    notifications: Vec<SingleNotificationResponse>,
+    next_cursor: Option<i64>,
+}
+
+fn list<TCon: Deref<Target = DbConnection>>(
+    connection: TCon,
+    token: String,
+    unread_only: bool,
+    before_id: Option<i64>,
+    limit: i64,
+) -> Result<APIResult<ListResponse>, WTError> {
+    let user_id = match user::get_user_id(&connection, &token)? {
+        UserLookup::Found(user_id) => user_id,
+        UserLookup::Disabled => return Ok(APIResult::error(ErrorCode::UserDisabled)),
+        UserLookup::NotFound => return Ok(APIResult::forbidden()),
+    };
+    let mut statement = mentions::table
+        .inner_join(
+            comments::table
+                .inner_join(chapters::table)
+                .inner_join(users::table),
+        )
+        .select((
+            mentions::table::all_columns(),
+            chapters::relative_path,
+            comments::table::all_columns(),
+            users::table::all_columns(),
+        ))
+        .filter(mentions::mentioned_user_id.eq(user_id))
+        .filter(comments::deleted.eq(false))
+        .order_by(mentions::id.desc())
+        // One extra row tells whether another page exists.
+        .limit(limit + 1)
+        .into_boxed();
+    if unread_only {
+        statement = statement.filter(mentions::read.eq(false));
+    }
+    if let Some(before_id) = before_id {
+        statement = statement.filter(mentions::id.lt(before_id));
+    }
+    let mut rows: Vec<(Mention, String, Comment, User)> = statement.load(&*connection)?;
+    let next_cursor = if rows.len() as i64 > limit {
+        rows.truncate(limit as usize);
+        rows.last().map(|(mention, ..)| mention.id)
+    } else {
+        None
+    };
+    let mut notified = Vec::with_capacity(rows.len());
+    let mut comments = Vec::with_capacity(rows.len());
+    for (mention, relative_path, comment, user) in rows {
+        notified.push(mention);
+        comments.push(SingleCommentQueryResult {
+            relative_path,
+            comment,
+            user,
+        });
+    }
+    Ok(APIResult::success_return(ListResponse {
+        notifications: notified
+            .into_iter()
+            .zip(convert_comment_query_results_to_response(comments))
+            .map(|(mention, comment)| SingleNotificationResponse {
+                id: mention.id,
+                read: mention.read,
+                timestamp: mention.timestamp,
+                comment,
+            })
+            .collect(),
+        next_cursor,
+    }))
+}
+
+#[post("/list")]
+async fn list_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<ListPayload>,
+) -> Result<impl Responder, WTError> {
+    if !user::is_token(&payload.token) {
+        return Ok(Either::Right(HttpResponse::Forbidden()));
+    }
+    let payload = payload.0;
+    let limit = payload
+        .limit
+        .unwrap_or(state.config.comment.recent_comments_amount)
+        .clamp(1, state.config.comment.max_page_size);
+    let connection = state.db_pool.get()?;
+    Ok(Either::Left(
+        common::block(move || {
+            list(
+                connection,
+                payload.token,
+                payload.unread_only,
+                payload.before_id,
+                limit,
+            )
+        })
+        .await??
+        .into_responder(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct MarkReadPayload {
+    token: String,
+    ids: Vec<i64>,
+}
+
+/// Marks the given mentions of the user as read, or all of them if `ids` is `None`.
+fn mark_read<TCon: Deref<Target = DbConnection>>(
+    connection: TCon,
+    token: String,
+    ids: Option<Vec<i64>>,
+) -> Result<APIResult, WTError> {
+    let user_id = match user::get_user_id(&connection, &token)? {
+        UserLookup::Found(user_id) => user_id,
+        UserLookup::Disabled => return Ok(APIResult::error(ErrorCode::UserDisabled)),
+        UserLookup::NotFound => return Ok(APIResult::forbidden()),
+    };
+    let statement = update(mentions::table)
+        .filter(mentions::mentioned_user_id.eq(user_id))
+        .filter(mentions::read.eq(false));
+    match ids {
+        Some(ids) => statement
+            .filter(mentions::id.eq_any(ids))
+            .set(mentions::read.eq(true))
+            .execute(&*connection)?,
+        None => statement
+            .set(mentions::read.eq(true))
+            .execute(&*connection)?,
+    };
+    Ok(APIResult::success())
+}
+
+#[post("/markRead")]
+async fn mark_read_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<MarkReadPayload>,
+) -> Result<impl Responder, WTError> {
+    if !user::is_token(&payload.token) {
+        return Ok(Either::Right(HttpResponse::Forbidden()));
+    }
+    if payload.0.ids.len() as i64 > state.config.comment.max_page_size {
+        return Ok(Either::Left(
+            APIResult::error(ErrorCode::ValidationFailed).into_responder(),
+        ));
+    }
+    let connection = state.db_pool.get()?;
+    Ok(Either::Left(
+        common::block(move || mark_read(connection, payload.0.token, Some(payload.0.ids)))
+            .await??
+            .into_responder(),
+    ))
+}
+
+#[derive(Deserialize)]
+struct MarkAllReadPayload {
+    token: String,
+}
+
+#[post("/markAllRead")]
+async fn mark_all_read_handler(
+    state: web::Data<AppState>,
+    payload: web::Json<MarkAllReadPayload>,
+) -> Result<impl Responder, WTError> {
+    if !user::is_token(&payload.token) {
+        return Ok(Either::Right(HttpResponse::Forbidden()));
+    }
+    let connection = state.db_pool.get()?;
+    Ok(Either::Left(
+        common::block(move || mark_read(connection, payload.0.token, None))
+            .await??
+            .into_responder(),
+    ))
+}
+
+pub fn get_service() -> impl HttpServiceFactory {
+    web::scope("/notification")
+        .service(list_handler)
+        .service(mark_read_handler)
+        .service(mark_all_read_handler)
+}
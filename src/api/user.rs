@@ -11,9 +11,11 @@ use serde::{Deserialize, Serialize};
 
 use crate::api::common;
 use crate::api::common::{APIResult, ErrorCode};
+use crate::api::notification;
 use crate::error::WTError;
 use crate::models::User;
-use crate::schema::{comments, mentions, users};
+use crate::response_cache::CacheGroup;
+use crate::schema::users;
 use crate::{AppState, DbConnection};
 
 pub const TOKEN_LENGTH: usize = 32;
@@ -53,31 +55,62 @@ fn validate_email(email: &str) -> Option<ErrorCode> {
     None
 }
 
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(rand::distributions::Alphanumeric)
+        .take(TOKEN_LENGTH)
+        .collect()
+}
+
 pub fn is_token(token: &str) -> bool {
     token.chars().all(|ch| ch.is_ascii_alphanumeric()) && token.len() == TOKEN_LENGTH
 }
 
-pub fn get_user(connection: &DbConnection, token: &str) -> Result<Option<User>, Error> {
+pub enum UserLookup<T> {
+    Found(T),
+    Disabled,
+    NotFound,
+}
+
+impl<T> UserLookup<T> {
+    /// Treats disabled accounts the same as unknown tokens.
+    pub fn found(self) -> Option<T> {
+        match self {
+            UserLookup::Found(value) => Some(value),
+            UserLookup::Disabled | UserLookup::NotFound => None,
+        }
+    }
+}
+
+pub fn get_user(connection: &DbConnection, token: &str) -> Result<UserLookup<User>, Error> {
     if !is_token(token) {
-        return Ok(None);
+        return Ok(UserLookup::NotFound);
     }
     let user: Option<User> = users::table
         .filter(users::token.eq(token))
         .first(connection)
         .optional()?;
-    Ok(user)
+    Ok(match user {
+        Some(user) if user.disabled => UserLookup::Disabled,
+        Some(user) => UserLookup::Found(user),
+        None => UserLookup::NotFound,
+    })
 }
 
-pub fn get_user_id(connection: &DbConnection, token: &str) -> Result<Option<i64>, Error> {
+pub fn get_user_id(connection: &DbConnection, token: &str) -> Result<UserLookup<i64>, Error> {
     if !is_token(token) {
-        return Ok(None);
+        return Ok(UserLookup::NotFound);
     }
-    let user_id: Option<i64> = users::table
+    let user: Option<(i64, bool)> = users::table
         .filter(users::token.eq(token))
-        .select(users::id)
+        .select((users::id, users::disabled))
         .first(connection)
         .optional()?;
-    Ok(user_id)
+    Ok(match user {
+        Some((_, true)) => UserLookup::Disabled,
+        Some((user_id, false)) => UserLookup::Found(user_id),
+        None => UserLookup::NotFound,
+    })
 }
 
 #[derive(Deserialize)]
@@ -100,25 +133,24 @@ async fn init_handler(
     query: web::Json<InitQuery>,
 ) -> Result<impl Responder, WTError> {
     let connection = state.db_pool.get()?;
-    if let Some(user) = web::block(move || get_user(&connection, &query.token)).await?? {
-        let statement = mentions::table
-            .inner_join(comments::table)
-            .filter(mentions::timestamp.ge(user.last_checked_mentions_timestamp))
-            .filter(mentions::mentioned_user_id.eq(user.id))
-            .filter(comments::deleted.eq(false))
-            .count();
-        let connection = state.db_pool.get()?;
-        let new_mentions: i64 = web::block(move || statement.get_result(&connection)).await??;
-        Ok(HttpResponse::Ok().json(InitResponse {
-            success: true,
-            user_name: user.user_name,
-            display_name: user.display_name,
-            email: user.email,
-            mentions: new_mentions,
-        }))
-    } else {
-        Ok(common::error_response())
-    }
+    let user = match common::block(move || get_user(&connection, &query.token)).await?? {
+        UserLookup::Found(user) => user,
+        UserLookup::Disabled => {
+            return Ok(common::error_response_with_code(ErrorCode::UserDisabled))
+        }
+        UserLookup::NotFound => return Ok(common::error_response()),
+    };
+    let user_id = user.id;
+    let connection = state.db_pool.get()?;
+    let new_mentions =
+        common::block(move || notification::count_unread(&connection, user_id)).await??;
+    Ok(HttpResponse::Ok().json(InitResponse {
+        success: true,
+        user_name: user.user_name,
+        display_name: user.display_name,
+        email: user.email,
+        mentions: new_mentions,
+    }))
 }
 
 #[derive(Deserialize)]
@@ -139,7 +171,6 @@ fn register<TCon: Deref<Target = DbConnection>>(
     user_name: String,
     display_name: String,
     email: Option<String>,
-    current_timestamp: i64,
 ) -> Result<APIResult<RegisterResponse>, WTError> {
     if diesel::select(diesel::dsl::exists(
         users::table.filter(
@@ -167,7 +198,6 @@ fn register<TCon: Deref<Target = DbConnection>>(
             users::display_name.eq(&display_name),
             users::email.eq(&email),
             users::token.eq(&token),
-            users::last_checked_mentions_timestamp.eq(current_timestamp),
         ))
         .execute(&*connection)?;
     Ok(APIResult::success_return(RegisterResponse {
@@ -189,22 +219,17 @@ async fn register_handler(
             return Ok(Either::Left(common::error_response_with_code(error_code)));
         }
     }
-    let token: String = rand::thread_rng()
-        .sample_iter(rand::distributions::Alphanumeric)
-        .take(TOKEN_LENGTH)
-        .collect();
+    let token = generate_token();
     let user_name = payload.display_name.replace(' ', "_").to_ascii_lowercase();
     let connection = state.db_pool.get()?;
-    let current_timestamp = common::get_current_timestamp();
     Ok(Either::Right(
-        web::block(move || {
+        common::block(move || {
             register(
                 connection,
                 token,
                 user_name,
                 payload.0.display_name,
                 payload.0.email,
-                current_timestamp,
             )
         })
         .await??
@@ -225,38 +250,38 @@ fn update_profile<TCon: Deref<Target = DbConnection>>(
     display_name: String,
     email: Option<String>,
 ) -> Result<APIResult, WTError> {
-    let user = get_user(&connection, &token)?;
-    if let Some(user) = user {
+    let user = match get_user(&connection, &token)? {
+        UserLookup::Found(user) => user,
+        UserLookup::Disabled => return Ok(APIResult::error(ErrorCode::UserDisabled)),
+        UserLookup::NotFound => return Ok(APIResult::forbidden()),
+    };
+    if diesel::select(diesel::dsl::exists(
+        users::table
+            .filter(users::token.ne(&token))
+            .filter(users::display_name.eq(&display_name)),
+    ))
+    .get_result(&*connection)?
+    {
+        return Ok(APIResult::error(ErrorCode::NameDuplicated));
+    }
+    if let Some(user_email) = &email {
         if diesel::select(diesel::dsl::exists(
             users::table
                 .filter(users::token.ne(&token))
-                .filter(users::display_name.eq(&display_name)),
+                .filter(users::email.eq(user_email)),
         ))
         .get_result(&*connection)?
         {
-            return Ok(APIResult::error(ErrorCode::NameDuplicated));
-        }
-        if let Some(user_email) = &email {
-            if diesel::select(diesel::dsl::exists(
-                users::table
-                    .filter(users::token.ne(&token))
-                    .filter(users::email.eq(user_email)),
-            ))
-            .get_result(&*connection)?
-            {
-                return Ok(APIResult::error(ErrorCode::EmailDuplicated));
-            }
+            return Ok(APIResult::error(ErrorCode::EmailDuplicated));
         }
-        diesel::update(&user)
-            .set((
-                users::email.eq(&email),
-                users::display_name.eq(&display_name),
-            ))
-            .execute(&*connection)?;
-        Ok(APIResult::success())
-    } else {
-        Ok(APIResult::forbidden())
     }
+    diesel::update(&user)
+        .set((
+            users::email.eq(&email),
+            users::display_name.eq(&display_name),
+        ))
+        .execute(&*connection)?;
+    Ok(APIResult::success())
 }
 
 #[post("/updateProfile")]
@@ -273,18 +298,20 @@ async fn update_profile_handler(
         }
     }
     let connection = state.db_pool.get()?;
-    Ok(Either::Right(
-        web::block(move || {
-            update_profile(
-                connection,
-                payload.0.token,
-                payload.0.display_name,
-                payload.0.email,
-            )
-        })
-        .await??
-        .into_responder(),
-    ))
+    let result = common::block(move || {
+        update_profile(
+            connection,
+            payload.0.token,
+            payload.0.display_name,
+            payload.0.email,
+        )
+    })
+    .await??;
+    if let APIResult::Success(_) = result {
+        // Display names and avatars are part of every comment.
+        state.response_cache.invalidate(CacheGroup::Comments);
+    }
+    Ok(Either::Right(result.into_responder()))
 }
 
 pub fn get_service() -> impl HttpServiceFactory {
@@ -0,0 +1,597 @@
+#[macro_use]
+extern crate diesel;
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use clap::{Parser, Subcommand};
+use comfy_table::Table;
+use diesel::dsl::{exists, not};
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Double, SmallInt};
+use dotenv::dotenv;
+
+use wt_analytics::api::admin::{self, ModerationAction, ModerationTarget, Role};
+use wt_analytics::api::{common, user};
+use wt_analytics::config::{Config, PagesConfig, RetentionConfig};
+use wt_analytics::export::{self, Export, ExportData, ExportFormat};
+use wt_analytics::models::{Chapter, User};
+use wt_analytics::pages::{self, Pages, UnknownPages};
+use wt_analytics::rollup::DAY_MILLISECONDS;
+use wt_analytics::schema::{
+    chapter_aliases, chapters, comments, quarantined_pages, reader_sketches, users,
+    visit_rollups_daily, visit_rollups_hourly, visits, visits_archive,
+};
+use wt_analytics::{retention, DbConnection};
+
+type CommandResult = Result<(), Box<dyn Error>>;
+
+/// Operational tasks against the database configured for the server.
+#[derive(Parser)]
+#[command(name = "wt_admin")]
+struct Cli {
+    /// Run the command inside a transaction that is rolled back instead of committed.
+    #[arg(long, global = true)]
+    dry_run: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect or apply the embedded migrations.
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Look up, disable, enable or sign out users, or change their role.
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Clean up comments.
+    #[command(subcommand)]
+    Comment(CommentCommand),
+    /// Rename, merge or clean up chapters.
+    #[command(subcommand)]
+    Chapter(ChapterCommand),
+    /// Remove raw visits and hourly rollups past their retention period.
+    #[command(subcommand)]
+    Retention(RetentionCommand),
+    /// Count WTCup votes.
+    #[command(subcommand)]
+    Votes(VotesCommand),
+    /// Write chapter totals, daily rollups or raw visits as CSV or JSON Lines.
+    Export {
+        /// One of chapters, daily or visits.
+        data: ExportData,
+        /// csv or jsonl.
+        #[arg(long, default_value = "csv")]
+        format: ExportFormat,
+        /// The first UTC day (YYYY-MM-DD) or millisecond timestamp to include.
+        #[arg(long, value_parser = export::parse_date)]
+        from: Option<i64>,
+        /// The UTC day or millisecond timestamp to stop before.
+        #[arg(long, value_parser = export::parse_date)]
+        to: Option<i64>,
+        /// Write to this file instead of stdout.
+        #[arg(long)]
+        output: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum MigrateCommand {
+    /// List migrations that have not been applied yet.
+    Status,
+    /// Apply pending migrations.
+    Run,
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    Show {
+        user_name: String,
+    },
+    Disable {
+        user_name: String,
+    },
+    Enable {
+        user_name: String,
+    },
+    /// Replace the user's token, signing them out everywhere, and print the new one.
+    ResetToken {
+        user_name: String,
+    },
+    /// Make the user a moderator or admin, or take that away again.
+    SetRole {
+        user_name: String,
+        /// One of user, moderator or admin.
+        role: Role,
+    },
+}
+
+#[derive(Subcommand)]
+enum CommentCommand {
+    /// Delete every comment of a user.
+    Purge { user_name: String },
+}
+
+#[derive(Subcommand)]
+enum ChapterCommand {
+    /// Change a chapter's path, keeping the old one as an alias. Fails if the new path already
+    /// belongs to another chapter; merge instead.
+    Rename { from: String, to: String },
+    /// Move the visits and comments of one chapter onto another and delete it, keeping its path
+    /// as an alias.
+    Merge { from: String, into: String },
+    /// Make a path that has no chapter of its own resolve to an existing chapter.
+    Alias { path: String, target: String },
+    /// Delete chapters that have no visits, comments or aliases, such as those created for
+    /// unknown paths before the page registry was enabled, and quarantined paths that have become
+    /// known or were not visited for pages.quarantine_days.
+    Cleanup,
+}
+
+#[derive(Subcommand)]
+enum RetentionCommand {
+    /// Apply the retention configuration once. The options override it.
+    Run {
+        #[arg(long)]
+        visit_days: Option<u32>,
+        #[arg(long)]
+        hourly_rollup_days: Option<u32>,
+        /// Move expired visits to visits_archive instead of deleting them.
+        #[arg(long)]
+        archive_visits: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum VotesCommand {
+    /// Print the number of votes and the average rating per chapter of a WTCup.
+    Tally { year: u16 },
+}
+
+fn find_user(connection: &DbConnection, user_name: &str) -> Result<User, Box<dyn Error>> {
+    users::table
+        .filter(users::user_name.eq(user_name))
+        .first(connection)
+        .optional()?
+        .ok_or_else(|| format!("No user named {:?}.", user_name).into())
+}
+
+fn find_chapter(connection: &DbConnection, relative_path: &str) -> Result<Chapter, Box<dyn Error>> {
+    chapters::table
+        .filter(chapters::relative_path.eq(relative_path))
+        .first(connection)
+        .optional()?
+        .ok_or_else(|| format!("No chapter at {:?}.", relative_path).into())
+}
+
+fn migrate(connection: &DbConnection, command: MigrateCommand) -> CommandResult {
+    let pending = wt_analytics::pending_migrations(connection)?;
+    if pending.is_empty() {
+        println!("The database is up to date.");
+        return Ok(());
+    }
+    let mut table = Table::new();
+    table.set_header(vec!["Pending migration"]);
+    for version in &pending {
+        table.add_row(vec![*version]);
+    }
+    println!("{}", table);
+    if let MigrateCommand::Run = command {
+        wt_analytics::run_migrations(connection)?;
+        println!("Applied {} migrations.", pending.len());
+    }
+    Ok(())
+}
+
+fn show_user(connection: &DbConnection, user_name: &str) -> CommandResult {
+    let user = find_user(connection, user_name)?;
+    let comment_count: i64 = comments::table
+        .filter(comments::user_id.eq(user.id))
+        .filter(comments::deleted.eq(false))
+        .count()
+        .get_result(connection)?;
+    let role = Role::of(&user);
+    let mut table = Table::new();
+    table.set_header(vec![
+        "Id",
+        "User name",
+        "Display name",
+        "Email",
+        "Role",
+        "Disabled",
+        "Comments",
+    ]);
+    table.add_row(vec![
+        user.id.to_string(),
+        user.user_name,
+        user.display_name,
+        user.email.unwrap_or_default(),
+        role.as_str().to_owned(),
+        user.disabled.to_string(),
+        comment_count.to_string(),
+    ]);
+    println!("{}", table);
+    Ok(())
+}
+
+fn set_user_disabled(connection: &DbConnection, user_name: &str, disabled: bool) -> CommandResult {
+    let user = find_user(connection, user_name)?;
+    if user.disabled == disabled {
+        println!("Nothing to do.");
+        return Ok(());
+    }
+    diesel::update(&user)
+        .set(users::disabled.eq(disabled))
+        .execute(connection)?;
+    let action = if disabled {
+        ModerationAction::DisableUser
+    } else {
+        ModerationAction::EnableUser
+    };
+    admin::log_action(connection, None, action, ModerationTarget::User(user.id))?;
+    println!(
+        "{} {}.",
+        if disabled { "Disabled" } else { "Enabled" },
+        user.user_name
+    );
+    Ok(())
+}
+
+fn set_role(connection: &DbConnection, user_name: &str, role: Role) -> CommandResult {
+    let user = find_user(connection, user_name)?;
+    if Role::of(&user) == role {
+        println!("Nothing to do.");
+        return Ok(());
+    }
+    diesel::update(&user)
+        .set(users::role.eq(role as i16))
+        .execute(connection)?;
+    admin::log_action(
+        connection,
+        None,
+        ModerationAction::SetRole,
+        ModerationTarget::Role(user.id, role),
+    )?;
+    println!("Set the role of {} to {}.", user.user_name, role.as_str());
+    Ok(())
+}
+
+fn reset_token(connection: &DbConnection, user_name: &str) -> CommandResult {
+    let user = find_user(connection, user_name)?;
+    let token = user::generate_token();
+    diesel::update(&user)
+        .set(users::token.eq(&token))
+        .execute(connection)?;
+    println!("New token for {}: {}", user.user_name, token);
+    Ok(())
+}
+
+fn purge_comments(connection: &DbConnection, user_name: &str) -> CommandResult {
+    let user = find_user(connection, user_name)?;
+    let deleted: Vec<i64> = diesel::update(
+        comments::table
+            .filter(comments::user_id.eq(user.id))
+            .filter(comments::deleted.eq(false)),
+    )
+    .set(comments::deleted.eq(true))
+    .returning(comments::id)
+    .get_results(connection)?;
+    for comment_id in &deleted {
+        admin::log_action(
+            connection,
+            None,
+            ModerationAction::DeleteComment,
+            ModerationTarget::Comment(*comment_id),
+        )?;
+    }
+    println!("Deleted {} comments of {}.", deleted.len(), user.user_name);
+    Ok(())
+}
+
+fn rename_chapter(connection: &DbConnection, from: &str, to: &str) -> CommandResult {
+    let chapter = find_chapter(connection, from)?;
+    if let Some(existing) = common::resolve_chapter(connection, to)? {
+        if existing.id != chapter.id {
+            return Err(
+                format!("{:?} already belongs to a chapter; merge them instead.", to).into(),
+            );
+        }
+    }
+    diesel::delete(chapter_aliases::table.filter(chapter_aliases::relative_path.eq(to)))
+        .execute(connection)?;
+    diesel::update(&chapter)
+        .set(chapters::relative_path.eq(to))
+        .execute(connection)?;
+    common::alias_chapter(connection, from, &chapter)?;
+    println!("Renamed {:?} to {:?}.", from, to);
+    Ok(())
+}
+
+fn alias_chapter(connection: &DbConnection, path: &str, target: &str) -> CommandResult {
+    if find_chapter(connection, path).is_ok() {
+        return Err(format!("{:?} has a chapter of its own; merge them instead.", path).into());
+    }
+    let chapter = find_chapter(connection, target)?;
+    common::alias_chapter(connection, path, &chapter)?;
+    println!("{:?} now resolves to {:?}.", path, target);
+    Ok(())
+}
+
+fn merge_chapters(connection: &DbConnection, from: &str, into: &str) -> CommandResult {
+    let from_chapter = find_chapter(connection, from)?;
+    let into_chapter = find_chapter(connection, into)?;
+    if from_chapter.id == into_chapter.id {
+        return Err("Cannot merge a chapter into itself.".into());
+    }
+    let counts = common::merge_chapters(connection, &from_chapter, &into_chapter)?;
+    admin::log_action(
+        connection,
+        None,
+        ModerationAction::MergeChapters,
+        ModerationTarget::Chapters {
+            from: from_chapter.relative_path.clone(),
+            into: into_chapter.relative_path.clone(),
+        },
+    )?;
+    let mut table = Table::new();
+    table.set_header(vec!["From", "Into", "Visits moved", "Comments moved"]);
+    table.add_row(vec![
+        from.to_owned(),
+        into.to_owned(),
+        counts.visits.to_string(),
+        counts.comments.to_string(),
+    ]);
+    println!("{}", table);
+    Ok(())
+}
+
+fn cleanup_chapters(connection: &DbConnection, pages_config: &PagesConfig) -> CommandResult {
+    let orphans: Vec<i32> = chapters::table
+        .filter(chapters::visit_count.eq(0))
+        .filter(not(exists(
+            visits::table.filter(visits::chapter_id.eq(chapters::id)),
+        )))
+        .filter(not(exists(
+            comments::table.filter(comments::chapter_id.eq(chapters::id)),
+        )))
+        .filter(not(exists(
+            chapter_aliases::table.filter(chapter_aliases::chapter_id.eq(chapters::id)),
+        )))
+        .filter(not(exists(
+            reader_sketches::table.filter(reader_sketches::chapter_id.eq(chapters::id)),
+        )))
+        .filter(not(exists(
+            visits_archive::table.filter(visits_archive::chapter_id.eq(chapters::id)),
+        )))
+        .filter(not(exists(
+            visit_rollups_daily::table.filter(visit_rollups_daily::chapter_id.eq(chapters::id)),
+        )))
+        .filter(not(exists(
+            visit_rollups_hourly::table.filter(visit_rollups_hourly::chapter_id.eq(chapters::id)),
+        )))
+        .select(chapters::id)
+        .for_update()
+        .load(connection)?;
+    let deleted: Vec<Chapter> =
+        diesel::delete(chapters::table.filter(chapters::id.eq_any(orphans)))
+            .get_results(connection)?;
+    if !deleted.is_empty() {
+        let mut table = Table::new();
+        table.set_header(vec!["Id", "Deleted chapter"]);
+        for chapter in &deleted {
+            table.add_row(vec![chapter.id.to_string(), chapter.relative_path.clone()]);
+        }
+        println!("{}", table);
+        println!("Deleted {} chapters.", deleted.len());
+    }
+    let removed = cleanup_quarantined_pages(connection, pages_config)?;
+    if removed > 0 {
+        println!("Removed {} quarantined pages.", removed);
+    }
+    if deleted.is_empty() && removed == 0 {
+        println!("Nothing to do.");
+    }
+    Ok(())
+}
+
+/// Removes quarantined paths that now match a page pattern or have a chapter, and those not
+/// visited for `pages.quarantine_days`.
+fn cleanup_quarantined_pages(
+    connection: &DbConnection,
+    pages_config: &PagesConfig,
+) -> Result<usize, Box<dyn Error>> {
+    let stored_patterns = pages::load_stored_patterns(connection)?;
+    // Under "allow" every path counts as known; only a matching pattern should free a path here.
+    let patterns_only = PagesConfig {
+        unknown: UnknownPages::Reject,
+        ..pages_config.clone()
+    };
+    let registry = Pages::load(&patterns_only, &stored_patterns)?.current();
+    let cutoff =
+        common::get_current_timestamp() - pages_config.quarantine_days as i64 * DAY_MILLISECONDS;
+    let quarantined: Vec<(String, i64)> = quarantined_pages::table
+        .select((
+            quarantined_pages::relative_path,
+            quarantined_pages::last_visit_timestamp,
+        ))
+        .for_update()
+        .load(connection)?;
+    let mut removed = Vec::new();
+    for (relative_path, last_visit_timestamp) in quarantined {
+        if last_visit_timestamp < cutoff
+            || registry.is_known(&relative_path)
+            || common::resolve_chapter(connection, &relative_path)?.is_some()
+        {
+            removed.push(relative_path);
+        }
+    }
+    Ok(diesel::delete(
+        quarantined_pages::table.filter(quarantined_pages::relative_path.eq_any(&removed)),
+    )
+    .execute(connection)?)
+}
+
+fn apply_retention(
+    connection: &DbConnection,
+    mut config: RetentionConfig,
+    visit_days: Option<u32>,
+    hourly_rollup_days: Option<u32>,
+    archive_visits: bool,
+) -> CommandResult {
+    config.visit_days = visit_days.or(config.visit_days);
+    config.hourly_rollup_days = hourly_rollup_days.or(config.hourly_rollup_days);
+    config.archive_visits |= archive_visits;
+    if config.visit_days.is_none() && config.hourly_rollup_days.is_none() {
+        return Err("No retention period is configured or given.".into());
+    }
+    let counts = retention::apply(connection, &config, common::get_current_timestamp())?;
+    let mut table = Table::new();
+    table.set_header(vec![
+        if config.archive_visits {
+            "Visits archived"
+        } else {
+            "Visits deleted"
+        },
+        "Hourly rollups deleted",
+    ]);
+    table.add_row(vec![
+        counts.visits.to_string(),
+        counts.hourly_rollups.to_string(),
+    ]);
+    println!("{}", table);
+    Ok(())
+}
+
+#[derive(QueryableByName)]
+struct TallyRow {
+    #[sql_type = "SmallInt"]
+    chapter_vote_id: i16,
+    #[sql_type = "BigInt"]
+    votes: i64,
+    #[sql_type = "Double"]
+    average: f64,
+}
+
+fn tally_votes(connection: &DbConnection, year: u16) -> CommandResult {
+    let table_name = match year {
+        2020 => "wtcup_2020_votes",
+        2021 => "wtcup_2021_votes",
+        2022 => "wtcup_2022_votes",
+        _ => return Err(format!("There was no WTCup in {}.", year).into()),
+    };
+    let rows: Vec<TallyRow> = sql_query(format!(
+        "SELECT chapter_vote_id, count(1) AS votes, avg(rating)::float8 AS average FROM {} \
+            GROUP BY chapter_vote_id ORDER BY average DESC, chapter_vote_id",
+        table_name
+    ))
+    .load(connection)?;
+    let mut table = Table::new();
+    table.set_header(vec!["Chapter vote id", "Votes", "Average rating"]);
+    for row in rows {
+        table.add_row(vec![
+            row.chapter_vote_id.to_string(),
+            row.votes.to_string(),
+            format!("{:.2}", row.average),
+        ]);
+    }
+    println!("{}", table);
+    Ok(())
+}
+
+fn export(connection: &DbConnection, mut export: Export, output: Option<&str>) -> CommandResult {
+    let mut writer: BufWriter<Box<dyn Write>> = BufWriter::new(match output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    });
+    while let Some(chunk) = export.next_chunk(connection)? {
+        writer.write_all(chunk.as_bytes())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn run(connection: &DbConnection, config: Config, command: Command) -> CommandResult {
+    match command {
+        Command::Migrate(command) => migrate(connection, command),
+        Command::User(UserCommand::Show { user_name }) => show_user(connection, &user_name),
+        Command::User(UserCommand::Disable { user_name }) => {
+            set_user_disabled(connection, &user_name, true)
+        }
+        Command::User(UserCommand::Enable { user_name }) => {
+            set_user_disabled(connection, &user_name, false)
+        }
+        Command::User(UserCommand::ResetToken { user_name }) => reset_token(connection, &user_name),
+        Command::User(UserCommand::SetRole { user_name, role }) => {
+            set_role(connection, &user_name, role)
+        }
+        Command::Comment(CommentCommand::Purge { user_name }) => {
+            purge_comments(connection, &user_name)
+        }
+        Command::Chapter(ChapterCommand::Rename { from, to }) => {
+            rename_chapter(connection, &from, &to)
+        }
+        Command::Chapter(ChapterCommand::Merge { from, into }) => {
+            merge_chapters(connection, &from, &into)
+        }
+        Command::Chapter(ChapterCommand::Alias { path, target }) => {
+            alias_chapter(connection, &path, &target)
+        }
+        Command::Chapter(ChapterCommand::Cleanup) => cleanup_chapters(connection, &config.pages),
+        Command::Retention(RetentionCommand::Run {
+            visit_days,
+            hourly_rollup_days,
+            archive_visits,
+        }) => apply_retention(
+            connection,
+            config.retention,
+            visit_days,
+            hourly_rollup_days,
+            archive_visits,
+        ),
+        Command::Votes(VotesCommand::Tally { year }) => tally_votes(connection, year),
+        Command::Export {
+            data,
+            format,
+            from,
+            to,
+            output,
+        } => export(
+            connection,
+            Export::new(data, format, from, to)?,
+            output.as_deref(),
+        ),
+    }
+}
+
+fn main() {
+    dotenv().ok();
+    let cli = Cli::parse();
+    let result = Config::load()
+        .map_err(Box::<dyn Error>::from)
+        .and_then(|config| {
+            let connection = DbConnection::establish(config.database_url())?;
+            if cli.dry_run {
+                // Never committed; rolled back when the connection is dropped.
+                connection.begin_test_transaction()?;
+                run(&connection, config, cli.command)?;
+                println!("Dry run, nothing was changed.");
+                Ok(())
+            } else if let Command::Retention(_) = cli.command {
+                // Commits batch by batch, so that a long run neither holds its locks nor loses its
+                // progress until the end.
+                run(&connection, config, cli.command)
+            } else if let Command::Export { .. } = cli.command {
+                // Only reads, chunk by chunk like the server's /admin/export.
+                run(&connection, config, cli.command)
+            } else {
+                connection.transaction(|| run(&connection, config, cli.command))
+            }
+        });
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
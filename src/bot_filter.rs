@@ -0,0 +1,245 @@
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use actix_web::http::header::{self, HeaderMap};
+use regex::{RegexSet, RegexSetBuilder};
+use serde::Deserialize;
+
+use crate::config::BotFilterConfig;
+use crate::rate_limit::Network;
+
+/// User agents of crawlers, link preview fetchers and HTTP libraries, matched case-insensitively.
+pub const DEFAULT_USER_AGENT_PATTERNS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "archiver",
+    "facebookexternalhit",
+    "embedly",
+    "preview",
+    "headless",
+    "lighthouse",
+    "^curl/",
+    "^wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "^java/",
+    "libwww-perl",
+    "httpclient",
+    "okhttp",
+    "^axios/",
+    "node-fetch",
+];
+
+/// What happens to visits the filter matches.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FilteredVisits {
+    /// Answered with 403 Forbidden.
+    Reject,
+    /// Answered like any other visit, but only counted in `wt_visits_filtered_total`.
+    Tally,
+}
+
+impl FromStr for FilteredVisits {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "reject" => Ok(FilteredVisits::Reject),
+            "tally" => Ok(FilteredVisits::Tally),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FilterReason {
+    DeniedAddress,
+    /// The user agent matched a pattern or was missing altogether.
+    UserAgent,
+    MissingOrigin,
+}
+
+impl FilterReason {
+    /// The `reason` label of `wt_visits_filtered_total`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FilterReason::DeniedAddress => "denied_address",
+            FilterReason::UserAgent => "user_agent",
+            FilterReason::MissingOrigin => "missing_origin",
+        }
+    }
+}
+
+/// Decides which visits are not counted. Only looks at the headers while handling the request;
+/// neither they nor the client address are kept.
+pub struct BotFilter {
+    enabled: bool,
+    action: FilteredVisits,
+    user_agents: RegexSet,
+    require_origin_or_referer: bool,
+    deny_list: Vec<Network>,
+}
+
+impl BotFilter {
+    pub fn new(config: &BotFilterConfig) -> Result<BotFilter, String> {
+        let user_agents = RegexSetBuilder::new(&config.user_agent_patterns)
+            .case_insensitive(true)
+            .build()
+            .map_err(|error| format!("bot_filter.user_agent_patterns is invalid: {}", error))?;
+        let deny_list = config
+            .deny_list
+            .iter()
+            .map(|entry| {
+                Network::parse(entry).ok_or_else(|| {
+                    format!(
+                        "bot_filter.deny_list contains {:?}, which is not an address or network",
+                        entry
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(BotFilter {
+            enabled: config.enabled,
+            action: config.action,
+            user_agents,
+            require_origin_or_referer: config.require_origin_or_referer,
+            deny_list,
+        })
+    }
+
+    pub fn action(&self) -> FilteredVisits {
+        self.action
+    }
+
+    /// Returns why a visit from `host` with `headers` should not be counted, if it should not.
+    pub fn check(&self, headers: &HeaderMap, host: &str) -> Option<FilterReason> {
+        if !self.enabled {
+            return None;
+        }
+        if !self.deny_list.is_empty() {
+            if let Ok(address) = host.parse::<IpAddr>() {
+                if self
+                    .deny_list
+                    .iter()
+                    .any(|network| network.contains(address))
+                {
+                    return Some(FilterReason::DeniedAddress);
+                }
+            }
+        }
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|user_agent| user_agent.to_str().ok())
+            .unwrap_or("");
+        if user_agent.trim().is_empty() || self.user_agents.is_match(user_agent) {
+            return Some(FilterReason::UserAgent);
+        }
+        if self.require_origin_or_referer
+            && !headers.contains_key(header::ORIGIN)
+            && !headers.contains_key(header::REFERER)
+        {
+            return Some(FilterReason::MissingOrigin);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    const BROWSER: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0";
+
+    fn filter(config: BotFilterConfig) -> BotFilter {
+        BotFilter::new(&BotFilterConfig {
+            deny_list: vec!["192.0.2.0/24".to_owned()],
+            ..config
+        })
+        .unwrap()
+    }
+
+    fn headers(pairs: &[(HeaderName, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(name.clone(), HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn browser_headers() -> HeaderMap {
+        headers(&[
+            (header::USER_AGENT, BROWSER),
+            (header::REFERER, "https://example.com/chapter"),
+        ])
+    }
+
+    #[test]
+    fn browser_visits_are_counted() {
+        let filter = filter(BotFilterConfig::default());
+        assert!(filter.check(&browser_headers(), "198.51.100.1").is_none());
+        let origin_only = headers(&[
+            (header::USER_AGENT, BROWSER),
+            (header::ORIGIN, "https://example.com"),
+        ]);
+        assert!(filter.check(&origin_only, "198.51.100.1").is_none());
+    }
+
+    #[test]
+    fn denied_addresses_are_filtered() {
+        let filter = filter(BotFilterConfig::default());
+        assert!(matches!(
+            filter.check(&browser_headers(), "192.0.2.10"),
+            Some(FilterReason::DeniedAddress)
+        ));
+    }
+
+    #[test]
+    fn crawler_and_missing_user_agents_are_filtered() {
+        let filter = filter(BotFilterConfig::default());
+        for user_agent in ["Mozilla/5.0 (compatible; Googlebot/2.1)", "curl/8.4.0", " "] {
+            let headers = headers(&[
+                (header::USER_AGENT, user_agent),
+                (header::REFERER, "https://example.com/chapter"),
+            ]);
+            assert!(matches!(
+                filter.check(&headers, "198.51.100.1"),
+                Some(FilterReason::UserAgent)
+            ));
+        }
+        let headers = headers(&[(header::REFERER, "https://example.com/chapter")]);
+        assert!(matches!(
+            filter.check(&headers, "198.51.100.1"),
+            Some(FilterReason::UserAgent)
+        ));
+    }
+
+    #[test]
+    fn visits_without_origin_or_referer_are_filtered() {
+        let headers = headers(&[(header::USER_AGENT, BROWSER)]);
+        let strict = filter(BotFilterConfig::default());
+        assert!(matches!(
+            strict.check(&headers, "198.51.100.1"),
+            Some(FilterReason::MissingOrigin)
+        ));
+        let lenient = filter(BotFilterConfig {
+            require_origin_or_referer: false,
+            ..BotFilterConfig::default()
+        });
+        assert!(lenient.check(&headers, "198.51.100.1").is_none());
+    }
+
+    #[test]
+    fn disabled_filter_counts_every_visit() {
+        let filter = filter(BotFilterConfig {
+            enabled: false,
+            ..BotFilterConfig::default()
+        });
+        assert!(filter.check(&HeaderMap::new(), "192.0.2.10").is_none());
+    }
+}
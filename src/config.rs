@@ -0,0 +1,764 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+use crate::api::analytics::LONGEST_TIME_FRAME_DAYS;
+use crate::api::comment::MIN_COMMENT_BYTES;
+use crate::api::common;
+use crate::bot_filter::{BotFilter, FilteredVisits, DEFAULT_USER_AGENT_PATTERNS};
+use crate::pages::{self, UnknownPages};
+use crate::rate_limit::{Network, RouteLimit};
+
+pub const CONFIG_PATH_VARIABLE: &str = "WT_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+// Width of comments.content in the database.
+const COMMENT_COLUMN_BYTES: usize = 4096;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(String, io::Error),
+    Parse(String, toml::de::Error),
+    Override(String, String),
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, error) => write!(f, "Failed to read {}: {}", path, error),
+            ConfigError::Parse(path, error) => write!(f, "Failed to parse {}: {}", path, error),
+            ConfigError::Override(variable, value) => {
+                write!(
+                    f,
+                    "Environment variable {} has invalid value {:?}",
+                    variable, value
+                )
+            }
+            ConfigError::Invalid(reason) => write!(f, "Invalid configuration: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind_addresses: Vec<String>,
+    pub allowed_origins: Vec<String>,
+    /// Defaults to the number of CPUs.
+    pub workers: Option<usize>,
+    /// How long in-flight requests may take to finish after SIGTERM or SIGINT.
+    pub shutdown_timeout_seconds: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind_addresses: vec!["127.0.0.1:8088".to_owned()],
+            allowed_origins: [
+                "https://wt.tepis.me",
+                "https://wt.bgme.bid",
+                "https://wt.bgme.me",
+                "https://rbq.desi",
+                "https://wt.0w0.bid",
+                "https://wt.umwings.com",
+                "https://wt.innocent.love",
+                "https://wt.mirror.0w0.bid",
+                "http://rintepis.gitgud.site",
+            ]
+            .iter()
+            .map(|origin| origin.to_string())
+            .collect(),
+            workers: None,
+            shutdown_timeout_seconds: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub url: Option<String>,
+    /// The maximum number of connections.
+    pub pool_size: u32,
+    /// Idle connections kept open; defaults to `pool_size`.
+    pub min_idle: Option<u32>,
+    pub connection_timeout_seconds: u64,
+    /// How long to keep retrying at startup while the database is unreachable.
+    pub startup_retry_seconds: u64,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: None,
+            pool_size: 10,
+            min_idle: None,
+            connection_timeout_seconds: 30,
+            startup_retry_seconds: 60,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnalyticsConfig {
+    pub page_size: i32,
+    /// Upper bound for the number of buckets a visit history request may cover.
+    pub max_history_buckets: i64,
+    /// How often finished hours and days of visits are added to the rollup tables.
+    pub rollup_interval_seconds: u64,
+    /// Visits are buffered in memory and written at this interval, or as soon as
+    /// `visit_flush_max_events` are buffered.
+    pub visit_flush_interval_seconds: u64,
+    pub visit_flush_max_events: usize,
+    /// Estimate unique readers per chapter and day from salted hashes of the client address and
+    /// user agent. The salt only lives in memory and is replaced daily.
+    pub unique_readers: bool,
+    /// The chapters compared by `/stats/funnel`, in reading order.
+    pub funnel_chapters: Vec<String>,
+    /// A file with more funnel chapters, one per line, appended to `funnel_chapters`.
+    pub funnel_manifest: Option<String>,
+}
+
+impl Default for AnalyticsConfig {
+    fn default() -> Self {
+        AnalyticsConfig {
+            page_size: 50,
+            max_history_buckets: 1000,
+            rollup_interval_seconds: 300,
+            visit_flush_interval_seconds: 5,
+            visit_flush_max_events: 1000,
+            unique_readers: false,
+            funnel_chapters: Vec::new(),
+            funnel_manifest: None,
+        }
+    }
+}
+
+impl AnalyticsConfig {
+    /// Reads `funnel_manifest` and returns the funnel chapters in order.
+    pub fn load_funnel_chapters(&self) -> Result<Vec<String>, String> {
+        let mut chapters = self.funnel_chapters.clone();
+        if let Some(manifest) = &self.funnel_manifest {
+            chapters.extend(
+                pages::read_manifest(manifest)
+                    .map_err(|error| format!("Failed to read {}: {}", manifest, error))?,
+            );
+        }
+        check_funnel_chapters(&chapters)?;
+        Ok(chapters)
+    }
+}
+
+fn check_funnel_chapters(chapters: &[String]) -> Result<(), String> {
+    for (index, chapter) in chapters.iter().enumerate() {
+        if !common::is_page_name(chapter) {
+            return Err(format!("{:?} is not a valid funnel chapter", chapter));
+        }
+        if chapters[..index].contains(chapter) {
+            return Err(format!(
+                "{:?} appears more than once in the funnel",
+                chapter
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CommentConfig {
+    pub max_comment_bytes: usize,
+    pub recent_comments_amount: i64,
+    pub max_reply_depth: usize,
+    pub max_page_size: i64,
+}
+
+impl Default for CommentConfig {
+    fn default() -> Self {
+        CommentConfig {
+            max_comment_bytes: 4096,
+            recent_comments_amount: 50,
+            max_reply_depth: 3,
+            max_page_size: 100,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    /// Take the client address from `X-Forwarded-For` when the peer is one of `trusted_proxies`.
+    pub trust_proxy_headers: bool,
+    /// Addresses or CIDR networks of the reverse proxies in front of the server.
+    pub trusted_proxies: Vec<String>,
+    pub routes: HashMap<String, RouteLimit>,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        let route = |burst, per_minute| RouteLimit { burst, per_minute };
+        RateLimitConfig {
+            enabled: true,
+            trust_proxy_headers: false,
+            trusted_proxies: Vec::new(),
+            routes: [
+                ("/stats/count", route(30, 60)),
+                ("/user/register", route(3, 2)),
+                ("/comment/send", route(5, 6)),
+                ("/comment/edit", route(10, 10)),
+            ]
+            .iter()
+            .map(|(path, limit)| (path.to_string(), *limit))
+            .collect(),
+        }
+    }
+}
+
+/// Visits from crawlers and link preview fetchers are answered without being counted.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotFilterConfig {
+    pub enabled: bool,
+    pub action: FilteredVisits,
+    /// Case-insensitive regular expressions. A missing user agent is always filtered.
+    pub user_agent_patterns: Vec<String>,
+    /// Browsers send at least one of them with the POST from a chapter page.
+    pub require_origin_or_referer: bool,
+    /// Client addresses or CIDR networks whose visits are never counted.
+    pub deny_list: Vec<String>,
+}
+
+impl Default for BotFilterConfig {
+    fn default() -> Self {
+        BotFilterConfig {
+            enabled: true,
+            action: FilteredVisits::Tally,
+            user_agent_patterns: DEFAULT_USER_AGENT_PATTERNS
+                .iter()
+                .map(|pattern| pattern.to_string())
+                .collect(),
+            require_origin_or_referer: true,
+            deny_list: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// A `tracing` filter such as `info` or `info,wt_analytics=debug`.
+    pub level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_owned(),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct PagesConfig {
+    pub unknown: UnknownPages,
+    /// A file with one pattern per line, read at startup.
+    pub manifest: Option<String>,
+    pub patterns: Vec<String>,
+    /// Quarantined paths beyond this many are not counted.
+    pub quarantine_max_pages: i64,
+    /// Quarantined paths not visited for this long make room for new ones and are removed by
+    /// `wt_admin chapter cleanup`.
+    pub quarantine_days: u32,
+    /// How often patterns stored through `/admin/setPagePatterns` are reloaded, so that changes
+    /// made through another instance take effect.
+    pub reload_interval_seconds: u64,
+}
+
+impl Default for PagesConfig {
+    fn default() -> Self {
+        PagesConfig {
+            unknown: UnknownPages::Allow,
+            manifest: None,
+            patterns: Vec::new(),
+            quarantine_max_pages: 10000,
+            quarantine_days: 30,
+            reload_interval_seconds: 60,
+        }
+    }
+}
+
+/// In-process caching of `/stats/chapters/all`, `/stats/chapters/recent` and `/comment/getRecent`.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    pub enabled: bool,
+    pub ttl_seconds: u64,
+    /// Sent as `Cache-Control: max-age` for browsers and CDNs, which are not told about comments
+    /// being sent or deleted.
+    pub max_age_seconds: u32,
+    pub max_entries: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            enabled: true,
+            ttl_seconds: 30,
+            max_age_seconds: 10,
+            max_entries: 1000,
+        }
+    }
+}
+
+/// `/metrics` is only served when at least one of these is set: on the public addresses it always
+/// requires the token, on `bind_address` only if one is configured.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct MetricsConfig {
+    pub bearer_token: Option<String>,
+    pub bind_address: Option<String>,
+}
+
+/// Raw visits and hourly rollups older than the configured number of days are removed, but never
+/// before the next coarser rollup covers them. Unset keeps them forever.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub visit_days: Option<u32>,
+    pub hourly_rollup_days: Option<u32>,
+    /// Move expired visits to `visits_archive` instead of deleting them.
+    pub archive_visits: bool,
+    /// Rows removed per transaction.
+    pub batch_size: i64,
+    pub interval_seconds: u64,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            visit_days: None,
+            hourly_rollup_days: None,
+            archive_visits: false,
+            batch_size: 10000,
+            interval_seconds: 3600,
+        }
+    }
+}
+
+/// The WTCup voting window, as millisecond timestamps.
+#[derive(Deserialize, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventConfig {
+    pub vote_start_timestamp: i64,
+    pub vote_end_timestamp: i64,
+}
+
+impl Default for EventConfig {
+    fn default() -> Self {
+        EventConfig {
+            vote_start_timestamp: 1672498800000,
+            vote_end_timestamp: 1674918000000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub analytics: AnalyticsConfig,
+    pub comment: CommentConfig,
+    pub rate_limit: RateLimitConfig,
+    pub bot_filter: BotFilterConfig,
+    pub cache: CacheConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+    pub pages: PagesConfig,
+    pub retention: RetentionConfig,
+    pub event: EventConfig,
+}
+
+fn parse_variable<T: FromStr>(variable: &str) -> Result<Option<T>, ConfigError> {
+    match env::var(variable) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|_| ConfigError::Override(variable.to_owned(), value)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn parse_list_variable(variable: &str) -> Option<Vec<String>> {
+    env::var(variable).ok().map(|value| {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_owned)
+            .collect()
+    })
+}
+
+impl Config {
+    /// Loads the configuration file named by `WT_CONFIG` (defaulting to `config.toml`, which may be
+    /// absent), then applies environment overrides and validates the result.
+    pub fn load() -> Result<Config, ConfigError> {
+        let (path, required) = match env::var(CONFIG_PATH_VARIABLE) {
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_CONFIG_PATH.to_owned(), false),
+        };
+        let mut config = match fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).map_err(|error| ConfigError::Parse(path, error))?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound && !required => Config::default(),
+            Err(error) => return Err(ConfigError::Read(path, error)),
+        };
+        config.apply_env_overrides()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn apply_env_overrides(&mut self) -> Result<(), ConfigError> {
+        if let Some(bind_addresses) = parse_list_variable("WT_SERVER_BIND_ADDRESSES") {
+            self.server.bind_addresses = bind_addresses;
+        }
+        if let Some(allowed_origins) = parse_list_variable("WT_SERVER_ALLOWED_ORIGINS") {
+            self.server.allowed_origins = allowed_origins;
+        }
+        if let Some(workers) = parse_variable("WT_SERVER_WORKERS")? {
+            self.server.workers = Some(workers);
+        }
+        if let Some(timeout) = parse_variable("WT_SERVER_SHUTDOWN_TIMEOUT_SECONDS")? {
+            self.server.shutdown_timeout_seconds = timeout;
+        }
+        if let Ok(url) = env::var("DATABASE_URL") {
+            self.database.url = Some(url);
+        }
+        if let Some(pool_size) = parse_variable("WT_DATABASE_POOL_SIZE")? {
+            self.database.pool_size = pool_size;
+        }
+        if let Some(min_idle) = parse_variable("WT_DATABASE_MIN_IDLE")? {
+            self.database.min_idle = Some(min_idle);
+        }
+        if let Some(timeout) = parse_variable("WT_DATABASE_CONNECTION_TIMEOUT_SECONDS")? {
+            self.database.connection_timeout_seconds = timeout;
+        }
+        if let Some(retry) = parse_variable("WT_DATABASE_STARTUP_RETRY_SECONDS")? {
+            self.database.startup_retry_seconds = retry;
+        }
+        if let Some(page_size) = parse_variable("WT_ANALYTICS_PAGE_SIZE")? {
+            self.analytics.page_size = page_size;
+        }
+        if let Some(max_history_buckets) = parse_variable("WT_ANALYTICS_MAX_HISTORY_BUCKETS")? {
+            self.analytics.max_history_buckets = max_history_buckets;
+        }
+        if let Some(interval) = parse_variable("WT_ANALYTICS_ROLLUP_INTERVAL_SECONDS")? {
+            self.analytics.rollup_interval_seconds = interval;
+        }
+        if let Some(interval) = parse_variable("WT_ANALYTICS_VISIT_FLUSH_INTERVAL_SECONDS")? {
+            self.analytics.visit_flush_interval_seconds = interval;
+        }
+        if let Some(max_events) = parse_variable("WT_ANALYTICS_VISIT_FLUSH_MAX_EVENTS")? {
+            self.analytics.visit_flush_max_events = max_events;
+        }
+        if let Some(unique_readers) = parse_variable("WT_ANALYTICS_UNIQUE_READERS")? {
+            self.analytics.unique_readers = unique_readers;
+        }
+        if let Some(funnel_chapters) = parse_list_variable("WT_ANALYTICS_FUNNEL_CHAPTERS") {
+            self.analytics.funnel_chapters = funnel_chapters;
+        }
+        if let Ok(funnel_manifest) = env::var("WT_ANALYTICS_FUNNEL_MANIFEST") {
+            self.analytics.funnel_manifest = Some(funnel_manifest);
+        }
+        if let Some(max_comment_bytes) = parse_variable("WT_COMMENT_MAX_COMMENT_BYTES")? {
+            self.comment.max_comment_bytes = max_comment_bytes;
+        }
+        if let Some(recent_comments_amount) = parse_variable("WT_COMMENT_RECENT_COMMENTS_AMOUNT")? {
+            self.comment.recent_comments_amount = recent_comments_amount;
+        }
+        if let Some(max_reply_depth) = parse_variable("WT_COMMENT_MAX_REPLY_DEPTH")? {
+            self.comment.max_reply_depth = max_reply_depth;
+        }
+        if let Some(max_page_size) = parse_variable("WT_COMMENT_MAX_PAGE_SIZE")? {
+            self.comment.max_page_size = max_page_size;
+        }
+        if let Some(enabled) = parse_variable("WT_RATE_LIMIT_ENABLED")? {
+            self.rate_limit.enabled = enabled;
+        }
+        if let Some(trust_proxy_headers) = parse_variable("WT_RATE_LIMIT_TRUST_PROXY_HEADERS")? {
+            self.rate_limit.trust_proxy_headers = trust_proxy_headers;
+        }
+        if let Some(trusted_proxies) = parse_list_variable("WT_RATE_LIMIT_TRUSTED_PROXIES") {
+            self.rate_limit.trusted_proxies = trusted_proxies;
+        }
+        if let Some(enabled) = parse_variable("WT_BOT_FILTER_ENABLED")? {
+            self.bot_filter.enabled = enabled;
+        }
+        if let Some(action) = parse_variable("WT_BOT_FILTER_ACTION")? {
+            self.bot_filter.action = action;
+        }
+        if let Some(require) = parse_variable("WT_BOT_FILTER_REQUIRE_ORIGIN_OR_REFERER")? {
+            self.bot_filter.require_origin_or_referer = require;
+        }
+        if let Some(deny_list) = parse_list_variable("WT_BOT_FILTER_DENY_LIST") {
+            self.bot_filter.deny_list = deny_list;
+        }
+        if let Some(enabled) = parse_variable("WT_CACHE_ENABLED")? {
+            self.cache.enabled = enabled;
+        }
+        if let Some(ttl) = parse_variable("WT_CACHE_TTL_SECONDS")? {
+            self.cache.ttl_seconds = ttl;
+        }
+        if let Some(max_age) = parse_variable("WT_CACHE_MAX_AGE_SECONDS")? {
+            self.cache.max_age_seconds = max_age;
+        }
+        if let Some(max_entries) = parse_variable("WT_CACHE_MAX_ENTRIES")? {
+            self.cache.max_entries = max_entries;
+        }
+        if let Ok(level) = env::var("WT_LOG_LEVEL") {
+            self.log.level = level;
+        }
+        if let Some(unknown) = parse_variable("WT_PAGES_UNKNOWN")? {
+            self.pages.unknown = unknown;
+        }
+        if let Ok(manifest) = env::var("WT_PAGES_MANIFEST") {
+            self.pages.manifest = Some(manifest);
+        }
+        if let Some(max_pages) = parse_variable("WT_PAGES_QUARANTINE_MAX_PAGES")? {
+            self.pages.quarantine_max_pages = max_pages;
+        }
+        if let Some(days) = parse_variable("WT_PAGES_QUARANTINE_DAYS")? {
+            self.pages.quarantine_days = days;
+        }
+        if let Some(interval) = parse_variable("WT_PAGES_RELOAD_INTERVAL_SECONDS")? {
+            self.pages.reload_interval_seconds = interval;
+        }
+        if let Ok(bearer_token) = env::var("WT_METRICS_BEARER_TOKEN") {
+            self.metrics.bearer_token = Some(bearer_token);
+        }
+        if let Ok(bind_address) = env::var("WT_METRICS_BIND_ADDRESS") {
+            self.metrics.bind_address = Some(bind_address);
+        }
+        if let Some(visit_days) = parse_variable("WT_RETENTION_VISIT_DAYS")? {
+            self.retention.visit_days = Some(visit_days);
+        }
+        if let Some(hourly_rollup_days) = parse_variable("WT_RETENTION_HOURLY_ROLLUP_DAYS")? {
+            self.retention.hourly_rollup_days = Some(hourly_rollup_days);
+        }
+        if let Some(archive_visits) = parse_variable("WT_RETENTION_ARCHIVE_VISITS")? {
+            self.retention.archive_visits = archive_visits;
+        }
+        if let Some(batch_size) = parse_variable("WT_RETENTION_BATCH_SIZE")? {
+            self.retention.batch_size = batch_size;
+        }
+        if let Some(interval) = parse_variable("WT_RETENTION_INTERVAL_SECONDS")? {
+            self.retention.interval_seconds = interval;
+        }
+        if let Some(start) = parse_variable("WT_EVENT_VOTE_START_TIMESTAMP")? {
+            self.event.vote_start_timestamp = start;
+        }
+        if let Some(end) = parse_variable("WT_EVENT_VOTE_END_TIMESTAMP")? {
+            self.event.vote_end_timestamp = end;
+        }
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |reason: String| Err(ConfigError::Invalid(reason));
+        if self.server.bind_addresses.is_empty() {
+            return invalid("server.bind_addresses must not be empty".to_owned());
+        }
+        for address in &self.server.bind_addresses {
+            if address.parse::<SocketAddr>().is_err() {
+                return invalid(format!(
+                    "server.bind_addresses contains {:?}, which is not an ip:port pair",
+                    address
+                ));
+            }
+        }
+        for origin in &self.server.allowed_origins {
+            if !(origin.starts_with("http://") || origin.starts_with("https://"))
+                || origin.ends_with('/')
+            {
+                return invalid(format!(
+                    "server.allowed_origins contains {:?}, which must look like https://example.com",
+                    origin
+                ));
+            }
+        }
+        if self.server.workers == Some(0) {
+            return invalid("server.workers must be at least 1".to_owned());
+        }
+        if self.database.url.is_none() {
+            return invalid(
+                "database.url must be set, either in the file or through DATABASE_URL".to_owned(),
+            );
+        }
+        if self.database.pool_size == 0 {
+            return invalid("database.pool_size must be at least 1".to_owned());
+        }
+        if self.database.min_idle > Some(self.database.pool_size) {
+            return invalid("database.min_idle must not exceed database.pool_size".to_owned());
+        }
+        if self.database.connection_timeout_seconds == 0 {
+            return invalid("database.connection_timeout_seconds must be at least 1".to_owned());
+        }
+        if self.analytics.page_size <= 0 {
+            return invalid("analytics.page_size must be at least 1".to_owned());
+        }
+        if self.analytics.max_history_buckets <= 0 {
+            return invalid("analytics.max_history_buckets must be at least 1".to_owned());
+        }
+        if self.analytics.rollup_interval_seconds == 0 {
+            return invalid("analytics.rollup_interval_seconds must be at least 1".to_owned());
+        }
+        if self.analytics.visit_flush_interval_seconds == 0 {
+            return invalid("analytics.visit_flush_interval_seconds must be at least 1".to_owned());
+        }
+        if self.analytics.visit_flush_max_events == 0 {
+            return invalid("analytics.visit_flush_max_events must be at least 1".to_owned());
+        }
+        if let Err(error) = check_funnel_chapters(&self.analytics.funnel_chapters) {
+            return invalid(format!("analytics.funnel_chapters: {}", error));
+        }
+        if self.comment.max_comment_bytes < MIN_COMMENT_BYTES
+            || self.comment.max_comment_bytes > COMMENT_COLUMN_BYTES
+        {
+            return invalid(format!(
+                "comment.max_comment_bytes must be between {} and {}",
+                MIN_COMMENT_BYTES, COMMENT_COLUMN_BYTES
+            ));
+        }
+        if self.comment.recent_comments_amount <= 0 {
+            return invalid("comment.recent_comments_amount must be at least 1".to_owned());
+        }
+        if self.comment.max_reply_depth == 0 {
+            return invalid("comment.max_reply_depth must be at least 1".to_owned());
+        }
+        if self.comment.max_page_size <= 0 {
+            return invalid("comment.max_page_size must be at least 1".to_owned());
+        }
+        for (route, limit) in &self.rate_limit.routes {
+            if !route.starts_with('/') {
+                return invalid(format!(
+                    "rate_limit.routes contains {:?}, which is not a path",
+                    route
+                ));
+            }
+            if limit.burst == 0 || limit.per_minute == 0 {
+                return invalid(format!(
+                    "rate_limit.routes.\"{}\" needs a burst and per_minute of at least 1",
+                    route
+                ));
+            }
+        }
+        for proxy in &self.rate_limit.trusted_proxies {
+            if Network::parse(proxy).is_none() {
+                return invalid(format!(
+                    "rate_limit.trusted_proxies contains {:?}, which is not an address or network",
+                    proxy
+                ));
+            }
+        }
+        if self.rate_limit.trust_proxy_headers && self.rate_limit.trusted_proxies.is_empty() {
+            return invalid(
+                "rate_limit.trust_proxy_headers needs rate_limit.trusted_proxies".to_owned(),
+            );
+        }
+        if let Err(error) = BotFilter::new(&self.bot_filter) {
+            return invalid(error);
+        }
+        if self.cache.ttl_seconds == 0 {
+            return invalid("cache.ttl_seconds must be at least 1".to_owned());
+        }
+        if self.cache.max_entries == 0 {
+            return invalid("cache.max_entries must be at least 1".to_owned());
+        }
+        if let Err(error) = EnvFilter::try_new(&self.log.level) {
+            return invalid(format!(
+                "log.level {:?} is invalid: {}",
+                self.log.level, error
+            ));
+        }
+        for pattern in &self.pages.patterns {
+            if !pages::is_valid_pattern(pattern) {
+                return invalid(format!(
+                    "pages.patterns contains invalid pattern {:?}",
+                    pattern
+                ));
+            }
+        }
+        if self.pages.quarantine_max_pages < 1 || self.pages.quarantine_days == 0 {
+            return invalid(
+                "pages.quarantine_max_pages and pages.quarantine_days must be at least 1"
+                    .to_owned(),
+            );
+        }
+        if self.pages.reload_interval_seconds == 0 {
+            return invalid("pages.reload_interval_seconds must be at least 1".to_owned());
+        }
+        if let Some(bearer_token) = &self.metrics.bearer_token {
+            if bearer_token.len() < 16 {
+                return invalid("metrics.bearer_token must be at least 16 characters".to_owned());
+            }
+        }
+        if let Some(address) = &self.metrics.bind_address {
+            if address.parse::<SocketAddr>().is_err() {
+                return invalid(format!(
+                    "metrics.bind_address {:?} is not an ip:port pair",
+                    address
+                ));
+            }
+            if self.server.bind_addresses.contains(address) {
+                return invalid(
+                    "metrics.bind_address must differ from server.bind_addresses".to_owned(),
+                );
+            }
+        }
+        if self.retention.visit_days == Some(0) || self.retention.hourly_rollup_days == Some(0) {
+            return invalid("retention days must be at least 1".to_owned());
+        }
+        // Recent counts read up to a day past the start of their time frame from hourly rollups.
+        if let Some(hourly_rollup_days) = self.retention.hourly_rollup_days {
+            if hourly_rollup_days <= LONGEST_TIME_FRAME_DAYS {
+                return invalid(format!(
+                    "retention.hourly_rollup_days must be at least {}",
+                    LONGEST_TIME_FRAME_DAYS + 1
+                ));
+            }
+        }
+        if self.retention.batch_size <= 0 {
+            return invalid("retention.batch_size must be at least 1".to_owned());
+        }
+        if self.retention.interval_seconds == 0 {
+            return invalid("retention.interval_seconds must be at least 1".to_owned());
+        }
+        if self.event.vote_start_timestamp >= self.event.vote_end_timestamp {
+            return invalid(
+                "event.vote_start_timestamp must be before event.vote_end_timestamp".to_owned(),
+            );
+        }
+        Ok(())
+    }
+
+    pub fn database_url(&self) -> &str {
+        self.database
+            .url
+            .as_deref()
+            .expect("database.url is checked during validation")
+    }
+}
@@ -2,39 +2,117 @@ use std::error::Error;
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
-use actix_web::http::{header, StatusCode};
+use actix_web::error::BlockingError;
+use actix_web::http::StatusCode;
 use actix_web::{error, HttpResponse};
+use diesel::r2d2::PoolError;
+use diesel::result::DatabaseErrorKind;
+use serde::Serialize;
+
+use crate::api::common::ErrorCode;
 
 #[derive(Debug)]
 pub enum WTError {
+    NotFound,
+    Forbidden,
+    Validation(String),
+    RateLimited,
+    DatabaseUnavailable(Box<dyn Error + Send>),
+    PoolTimeout(Box<dyn Error + Send>),
     InternalError(Box<dyn Error + Send>),
 }
 
-impl<T: Error + Send + 'static> From<T> for WTError {
-    fn from(error: T) -> Self {
+impl From<diesel::result::Error> for WTError {
+    fn from(error: diesel::result::Error) -> Self {
+        match error {
+            diesel::result::Error::NotFound => WTError::NotFound,
+            diesel::result::Error::DatabaseError(DatabaseErrorKind::UnableToSendCommand, _) => {
+                WTError::DatabaseUnavailable(Box::new(error))
+            }
+            error => WTError::InternalError(Box::new(error)),
+        }
+    }
+}
+
+impl From<PoolError> for WTError {
+    fn from(error: PoolError) -> Self {
+        WTError::PoolTimeout(Box::new(error))
+    }
+}
+
+impl From<BlockingError> for WTError {
+    fn from(error: BlockingError) -> Self {
         WTError::InternalError(Box::new(error))
     }
 }
 
+impl WTError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            WTError::NotFound => ErrorCode::NotFound,
+            WTError::Forbidden => ErrorCode::Forbidden,
+            WTError::Validation(_) => ErrorCode::ValidationFailed,
+            WTError::RateLimited => ErrorCode::RateLimited,
+            WTError::DatabaseUnavailable(_) => ErrorCode::DatabaseUnavailable,
+            WTError::PoolTimeout(_) => ErrorCode::PoolTimeout,
+            WTError::InternalError(_) => ErrorCode::InternalError,
+        }
+    }
+
+    pub fn cause(&self) -> Option<&(dyn Error + Send)> {
+        match self {
+            WTError::DatabaseUnavailable(cause)
+            | WTError::PoolTimeout(cause)
+            | WTError::InternalError(cause) => Some(cause.as_ref()),
+            _ => None,
+        }
+    }
+}
+
 impl Display for WTError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "An internal error occurred.")
-        // match self {
-        //     WTError::InternalError(_) => write!(f, "An internal error occurred."),
-        // }
+        match self {
+            WTError::NotFound => write!(f, "Not found."),
+            WTError::Forbidden => write!(f, "Forbidden."),
+            WTError::Validation(reason) => write!(f, "Invalid request: {}", reason),
+            WTError::RateLimited => write!(f, "Too many requests."),
+            WTError::DatabaseUnavailable(_) => write!(f, "The database is unavailable."),
+            WTError::PoolTimeout(_) => write!(f, "Timed out waiting for a database connection."),
+            WTError::InternalError(_) => write!(f, "An internal error occurred."),
+        }
     }
 }
 
+#[derive(Serialize)]
+struct ErrorBody {
+    success: bool,
+    code: ErrorCode,
+    correlation_id: String,
+}
+
 impl error::ResponseError for WTError {
     fn status_code(&self) -> StatusCode {
         match *self {
+            WTError::NotFound => StatusCode::NOT_FOUND,
+            WTError::Forbidden => StatusCode::FORBIDDEN,
+            WTError::Validation(_) => StatusCode::BAD_REQUEST,
+            WTError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            WTError::DatabaseUnavailable(_) | WTError::PoolTimeout(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
             WTError::InternalError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
     fn error_response(&self) -> HttpResponse {
-        eprintln!("{:?}", self);
-        HttpResponse::build(self.status_code())
-            .insert_header((header::CONTENT_TYPE, "text/html; charset=utf-8"))
-            .body(self.to_string())
+        // Lets a user's bug report be matched with the logged cause.
+        let correlation_id = format!("{:016x}", rand::random::<u64>());
+        if let Some(cause) = self.cause() {
+            tracing::error!(correlation_id = %correlation_id, cause = ?cause, "{}", self);
+        }
+        HttpResponse::build(self.status_code()).json(ErrorBody {
+            success: false,
+            code: self.code(),
+            correlation_id,
+        })
     }
 }
@@ -0,0 +1,368 @@
+use std::str::FromStr;
+
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{BigInt, Bigint, Integer, VarChar};
+use indoc::indoc;
+use serde::{Deserialize, Serialize};
+
+use crate::rollup::DAY_MILLISECONDS;
+use crate::DbConnection;
+
+/// Rows read from the database per chunk of an export.
+const CHUNK_ROWS: i64 = 10000;
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportData {
+    /// All-time totals from `chapters`; the range does not apply.
+    Chapters,
+    /// Visits per chapter and UTC day from `visit_rollups_daily`, for the days rolled up so far.
+    Daily,
+    /// Raw visits, including those moved to `visits_archive`.
+    Visits,
+}
+
+impl FromStr for ExportData {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "chapters" => Ok(ExportData::Chapters),
+            "daily" => Ok(ExportData::Daily),
+            "visits" => Ok(ExportData::Visits),
+            _ => Err("expected chapters, daily or visits".to_owned()),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Jsonl,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv; charset=utf-8",
+            ExportFormat::Jsonl => "application/x-ndjson",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Jsonl => "jsonl",
+        }
+    }
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "csv" => Ok(ExportFormat::Csv),
+            "jsonl" => Ok(ExportFormat::Jsonl),
+            _ => Err("expected csv or jsonl".to_owned()),
+        }
+    }
+}
+
+/// Parses a UTC date as `YYYY-MM-DD` into the timestamp of its midnight, or passes a timestamp in
+/// milliseconds through.
+pub fn parse_date(value: &str) -> Result<i64, String> {
+    if let Ok(timestamp) = value.parse() {
+        return Ok(timestamp);
+    }
+    let invalid = || format!("{:?} is neither a YYYY-MM-DD date nor a timestamp", value);
+    let mut parts = value.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(Some(year)), Some(Some(month)), Some(Some(day))) => (year, month, day),
+        _ => return Err(invalid()),
+    };
+    let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let month_days = match month {
+        2 if leap_year => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1..=12).contains(&month) || !(1..=month_days).contains(&day) {
+        return Err(invalid());
+    }
+    // Days since 1970-01-01 in the proleptic Gregorian calendar, with years starting in March.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    Ok((era * 146097 + day_of_era - 719468) * DAY_MILLISECONDS)
+}
+
+#[derive(QueryableByName, Serialize)]
+struct ChapterRow {
+    #[sql_type = "Integer"]
+    id: i32,
+    #[sql_type = "VarChar"]
+    relative_path: String,
+    #[sql_type = "BigInt"]
+    visit_count: i64,
+}
+
+#[derive(QueryableByName, Serialize)]
+struct DailyRow {
+    #[sql_type = "Integer"]
+    chapter_id: i32,
+    #[sql_type = "VarChar"]
+    relative_path: String,
+    #[sql_type = "BigInt"]
+    day_start: i64,
+    #[sql_type = "BigInt"]
+    visit_count: i64,
+}
+
+#[derive(QueryableByName, Serialize)]
+struct VisitRow {
+    #[sql_type = "BigInt"]
+    id: i64,
+    #[sql_type = "Integer"]
+    chapter_id: i32,
+    #[sql_type = "VarChar"]
+    relative_path: String,
+    #[sql_type = "BigInt"]
+    timestamp: i64,
+}
+
+/// Quotes `value` as needed. Paths come from visitors, so those that a spreadsheet would read as a
+/// formula are prefixed with `'` to keep them text.
+fn csv_field(value: &str) -> String {
+    let value = if value.starts_with(&['=', '+', '-', '@', '\t', '\r'][..]) {
+        format!("'{}", value)
+    } else {
+        value.to_owned()
+    };
+    if value.contains(&[',', '"', '\r', '\n'][..]) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value
+    }
+}
+
+/// Formats the rows of one chunk, each on its own line.
+fn format_rows<T: Serialize>(
+    format: ExportFormat,
+    rows: &[T],
+    csv_fields: impl Fn(&T) -> Vec<String>,
+) -> String {
+    let mut output = String::new();
+    for row in rows {
+        match format {
+            ExportFormat::Csv => output.push_str(&csv_fields(row).join(",")),
+            ExportFormat::Jsonl => {
+                output.push_str(&serde_json::to_string(row).expect("rows serialize to JSON"))
+            }
+        }
+        output.push('\n');
+    }
+    output
+}
+
+/// Reads an export chunk by chunk, continuing after the last row of the previous chunk so that
+/// nothing is held in memory beyond one chunk. Chunks are read independently: rows written during
+/// the export may or may not be included.
+pub struct Export {
+    data: ExportData,
+    format: ExportFormat,
+    from: i64,
+    to: i64,
+    /// The sort key of the last row written, or `None` before the first chunk.
+    after: Option<(i64, i64)>,
+    done: bool,
+}
+
+impl Export {
+    /// Exports `data` timestamped within `from` (inclusive) and `to` (exclusive).
+    pub fn new(
+        data: ExportData,
+        format: ExportFormat,
+        from: Option<i64>,
+        to: Option<i64>,
+    ) -> Result<Export, String> {
+        if data == ExportData::Chapters && (from.is_some() || to.is_some()) {
+            return Err("chapter totals cannot be limited to a range".to_owned());
+        }
+        let (from, to) = (from.unwrap_or(i64::MIN), to.unwrap_or(i64::MAX));
+        if from >= to {
+            return Err("the range must end after it starts".to_owned());
+        }
+        Ok(Export {
+            data,
+            format,
+            from,
+            to,
+            after: None,
+            done: false,
+        })
+    }
+
+    fn header(&self) -> &'static str {
+        match self.data {
+            ExportData::Chapters => "id,relative_path,visit_count\n",
+            ExportData::Daily => "chapter_id,relative_path,day_start,visit_count\n",
+            ExportData::Visits => "id,chapter_id,relative_path,timestamp\n",
+        }
+    }
+
+    /// Returns the next chunk of formatted rows, starting with the CSV header, or `None` once
+    /// everything has been returned.
+    pub fn next_chunk(&mut self, connection: &DbConnection) -> QueryResult<Option<String>> {
+        if self.done {
+            return Ok(None);
+        }
+        let mut output = match (self.after, self.format) {
+            (None, ExportFormat::Csv) => self.header().to_owned(),
+            _ => String::new(),
+        };
+        let (first, second) = self.after.unwrap_or((i64::MIN, i64::MIN));
+        let (rows, last) = match self.data {
+            ExportData::Chapters => {
+                let rows: Vec<ChapterRow> = sql_query(indoc! {"
+                    SELECT id, relative_path, visit_count FROM chapters
+                        WHERE id > $1
+                        ORDER BY id
+                        LIMIT $2
+                "})
+                .bind::<Bigint, i64>(first)
+                .bind::<Bigint, i64>(CHUNK_ROWS)
+                .load(connection)?;
+                output.push_str(&format_rows(self.format, &rows, |row| {
+                    vec![
+                        row.id.to_string(),
+                        csv_field(&row.relative_path),
+                        row.visit_count.to_string(),
+                    ]
+                }));
+                (rows.len(), rows.last().map(|row| (i64::from(row.id), 0)))
+            }
+            ExportData::Daily => {
+                let rows: Vec<DailyRow> = sql_query(indoc! {"
+                    SELECT visit_rollups_daily.chapter_id, chapters.relative_path,
+                            visit_rollups_daily.bucket_start AS day_start,
+                            visit_rollups_daily.visit_count
+                        FROM visit_rollups_daily
+                        INNER JOIN chapters
+                            ON visit_rollups_daily.chapter_id = chapters.id
+                        WHERE (visit_rollups_daily.chapter_id, visit_rollups_daily.bucket_start)
+                                > ($1, $2)
+                            AND visit_rollups_daily.bucket_start >= $3
+                            AND visit_rollups_daily.bucket_start < $4
+                        ORDER BY visit_rollups_daily.chapter_id, visit_rollups_daily.bucket_start
+                        LIMIT $5
+                "})
+                .bind::<Bigint, i64>(first)
+                .bind::<Bigint, i64>(second)
+                .bind::<Bigint, i64>(self.from)
+                .bind::<Bigint, i64>(self.to)
+                .bind::<Bigint, i64>(CHUNK_ROWS)
+                .load(connection)?;
+                output.push_str(&format_rows(self.format, &rows, |row| {
+                    vec![
+                        row.chapter_id.to_string(),
+                        csv_field(&row.relative_path),
+                        row.day_start.to_string(),
+                        row.visit_count.to_string(),
+                    ]
+                }));
+                let last = rows
+                    .last()
+                    .map(|row| (i64::from(row.chapter_id), row.day_start));
+                (rows.len(), last)
+            }
+            ExportData::Visits => {
+                // Archived visits keep their ids, so both tables together are ordered by id.
+                let rows: Vec<VisitRow> = sql_query(indoc! {"
+                    SELECT visits.id, visits.chapter_id, chapters.relative_path, visits.timestamp
+                        FROM (
+                            (SELECT id, chapter_id, timestamp FROM visits
+                                WHERE id > $1 AND timestamp >= $2 AND timestamp < $3
+                                ORDER BY id LIMIT $4)
+                            UNION ALL
+                            (SELECT id, chapter_id, timestamp FROM visits_archive
+                                WHERE id > $1 AND timestamp >= $2 AND timestamp < $3
+                                ORDER BY id LIMIT $4)
+                        ) AS visits
+                        INNER JOIN chapters
+                            ON visits.chapter_id = chapters.id
+                        ORDER BY visits.id
+                        LIMIT $4
+                "})
+                .bind::<Bigint, i64>(first)
+                .bind::<Bigint, i64>(self.from)
+                .bind::<Bigint, i64>(self.to)
+                .bind::<Bigint, i64>(CHUNK_ROWS)
+                .load(connection)?;
+                output.push_str(&format_rows(self.format, &rows, |row| {
+                    vec![
+                        row.id.to_string(),
+                        row.chapter_id.to_string(),
+                        csv_field(&row.relative_path),
+                        row.timestamp.to_string(),
+                    ]
+                }));
+                (rows.len(), rows.last().map(|row| (row.id, 0)))
+            }
+        };
+        self.done = (rows as i64) < CHUNK_ROWS;
+        if last.is_some() {
+            self.after = last;
+        }
+        Ok(Some(output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_date_reads_utc_midnight() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date("2000-03-01"), Ok(951868800000));
+        assert_eq!(parse_date("2024-02-29"), Ok(1709164800000));
+        assert_eq!(parse_date("1969-12-31"), Ok(-DAY_MILLISECONDS));
+    }
+
+    #[test]
+    fn parse_date_passes_timestamps_through() {
+        assert_eq!(parse_date("1700000000000"), Ok(1700000000000));
+    }
+
+    #[test]
+    fn parse_date_rejects_other_input() {
+        assert!(parse_date("2026-02-30").is_err());
+        assert!(parse_date("2023-02-29").is_err());
+        assert!(parse_date("1900-02-29").is_err());
+        assert!(parse_date("2026-04-31").is_err());
+        assert!(parse_date("2026-13-01").is_err());
+        assert!(parse_date("2026-01-00").is_err());
+        assert!(parse_date("2026-01").is_err());
+        assert!(parse_date("yesterday").is_err());
+    }
+
+    #[test]
+    fn csv_field_quotes_separators() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+    }
+
+    #[test]
+    fn csv_field_keeps_formulas_as_text() {
+        assert_eq!(csv_field("=HYPERLINK(1)"), "'=HYPERLINK(1)");
+        assert_eq!(csv_field("@SUM(1)"), "'@SUM(1)");
+        assert_eq!(csv_field("-1,2"), "\"'-1,2\"");
+    }
+}
@@ -0,0 +1,76 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix::fut::wrap_future;
+use actix::{Actor, ActorFutureExt, AsyncContext, Context};
+use diesel::r2d2::{ConnectionManager, Pool};
+
+use crate::api::common;
+use crate::error::WTError;
+use crate::DbConnection;
+
+/// Runs `job` on the blocking thread pool right away and then every `interval`. Ticks are skipped
+/// while a previous run, such as a first backfill, is still going.
+pub struct PeriodicJob<F> {
+    name: &'static str,
+    db_pool: Pool<ConnectionManager<DbConnection>>,
+    interval: Duration,
+    job: Arc<F>,
+    running: bool,
+}
+
+impl<F> PeriodicJob<F>
+where
+    F: Fn(&DbConnection) -> Result<(), WTError> + Send + Sync + 'static,
+{
+    pub fn new(
+        name: &'static str,
+        db_pool: Pool<ConnectionManager<DbConnection>>,
+        interval: Duration,
+        job: F,
+    ) -> Self {
+        PeriodicJob {
+            name,
+            db_pool,
+            interval,
+            job: Arc::new(job),
+            running: false,
+        }
+    }
+
+    fn tick(&mut self, ctx: &mut Context<Self>) {
+        if self.running {
+            return;
+        }
+        self.running = true;
+        let db_pool = self.db_pool.clone();
+        let job = self.job.clone();
+        let work = common::block(move || -> Result<(), WTError> {
+            let connection = db_pool.get()?;
+            job(&connection)
+        });
+        ctx.spawn(wrap_future(work).map(|result, actor: &mut Self, _| {
+            actor.running = false;
+            if let Err(error) = result.map_err(WTError::from).and_then(|result| result) {
+                tracing::error!(
+                    job = actor.name,
+                    error = %error,
+                    cause = ?error.cause(),
+                    "periodic job failed"
+                );
+            }
+        }));
+    }
+}
+
+impl<F> Actor for PeriodicJob<F>
+where
+    F: Fn(&DbConnection) -> Result<(), WTError> + Send + Sync + 'static,
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        self.tick(ctx);
+        ctx.run_interval(self.interval, |actor, ctx| actor.tick(ctx));
+    }
+}
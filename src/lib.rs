@@ -0,0 +1,87 @@
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+#[macro_use]
+extern crate lazy_static;
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use actix::Addr;
+use diesel::dsl::sql;
+use diesel::r2d2::{ConnectionManager, Pool};
+use diesel::sql_types::Bool;
+use diesel::PgConnection;
+use diesel::{QueryResult, RunQueryDsl};
+use diesel_migrations::{MigrationConnection, RunMigrationsError};
+
+use crate::bot_filter::BotFilter;
+use crate::config::Config;
+use crate::pages::Pages;
+use crate::rate_limit::RateLimiter;
+use crate::readers::ReaderSalt;
+use crate::response_cache::ResponseCache;
+use crate::visit_buffer::VisitBuffer;
+
+pub mod api;
+pub mod bot_filter;
+pub mod config;
+mod dark_colors;
+pub mod error;
+pub mod export;
+pub mod jobs;
+pub mod logging;
+pub mod metrics;
+pub mod models;
+pub mod pages;
+pub mod rate_limit;
+pub mod readers;
+pub mod response_cache;
+pub mod retention;
+pub mod rollup;
+pub mod schema;
+pub mod visit_buffer;
+
+embed_migrations!();
+
+// Versions of the embedded migrations in the form diesel records them, set by build.rs.
+const MIGRATION_VERSIONS: &str = env!("WT_MIGRATION_VERSIONS");
+
+pub type DbConnection = PgConnection;
+
+pub struct AppState {
+    pub db_pool: Pool<ConnectionManager<DbConnection>>,
+    pub config: Config,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub bot_filter: BotFilter,
+    pub response_cache: ResponseCache,
+    pub pages: Arc<Pages>,
+    pub visit_buffer: Addr<VisitBuffer>,
+    /// Set when unique readers are estimated.
+    pub reader_salt: Option<ReaderSalt>,
+    /// The chapters compared by `/stats/funnel`, in reading order.
+    pub funnel_chapters: Vec<String>,
+}
+
+pub fn run_migrations(connection: &DbConnection) -> Result<(), RunMigrationsError> {
+    embedded_migrations::run(connection)
+}
+
+/// Returns the embedded migrations that have not been applied to the database yet, which are all
+/// of them if diesel has not created its bookkeeping table yet.
+pub fn pending_migrations(connection: &DbConnection) -> QueryResult<Vec<&'static str>> {
+    let migrated = diesel::select(sql::<Bool>(
+        "to_regclass('__diesel_schema_migrations') IS NOT NULL",
+    ))
+    .get_result(connection)?;
+    let applied = if migrated {
+        connection.previously_run_migration_versions()?
+    } else {
+        HashSet::new()
+    };
+    Ok(MIGRATION_VERSIONS
+        .split(',')
+        .filter(|version| !applied.contains(*version))
+        .collect())
+}
@@ -0,0 +1,111 @@
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Instant;
+
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::Error;
+use tracing::Instrument;
+use tracing_subscriber::EnvFilter;
+
+use crate::config::LogConfig;
+use crate::metrics;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Installs a subscriber writing one JSON object per line to stdout.
+pub fn init(config: &LogConfig) {
+    tracing_subscriber::fmt()
+        .json()
+        .flatten_event(true)
+        .with_current_span(false)
+        .with_env_filter(EnvFilter::new(&config.level))
+        .init();
+}
+
+/// Middleware emitting one log line per request with its route pattern, status and latency.
+///
+/// Only the matched route pattern is logged, never the raw path, query string or body, so tokens
+/// and visited pages do not end up in the logs.
+pub struct RequestLogger;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestLogger
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestLoggerMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestLoggerMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct RequestLoggerMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestLoggerMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        let request_id = format!("{:016x}", rand::random::<u64>());
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %request.method(),
+        );
+        let method = request.method().to_string();
+        let start = Instant::now();
+        let service = self.service.clone();
+        Box::pin(
+            async move {
+                let result = service.call(request).await;
+                let elapsed = start.elapsed().as_secs_f64();
+                let latency_ms = elapsed * 1000.0;
+                match result {
+                    Ok(mut response) => {
+                        let route = response
+                            .request()
+                            .match_pattern()
+                            .unwrap_or_else(|| "<unmatched>".to_owned());
+                        let status = response.status().as_u16();
+                        metrics::observe_request(&route, &method, status, elapsed);
+                        tracing::info!(
+                            route = %route,
+                            status,
+                            latency_ms,
+                            "request completed"
+                        );
+                        if let Ok(value) = HeaderValue::from_str(&request_id) {
+                            response
+                                .headers_mut()
+                                .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+                        }
+                        Ok(response)
+                    }
+                    Err(error) => {
+                        tracing::warn!(latency_ms, error = %error, "request failed");
+                        Err(error)
+                    }
+                }
+            }
+            .instrument(span),
+        )
+    }
+}
@@ -1,71 +1,208 @@
-#[macro_use]
-extern crate diesel;
-#[macro_use]
-extern crate diesel_migrations;
-#[macro_use]
-extern crate lazy_static;
-
-use std::env;
+use std::fmt::Display;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use actix::Actor;
 use actix_cors::Cors;
-use actix_web::{App, HttpServer};
+use actix_web::{web, App, HttpServer};
 use diesel::r2d2::{ConnectionManager, Pool};
-use diesel::PgConnection;
+use diesel::{Connection, ConnectionResult};
 use dotenv::dotenv;
 
-mod api;
-mod dark_colors;
-mod error;
-mod models;
-pub mod schema;
+use wt_analytics::bot_filter::BotFilter;
+use wt_analytics::config::{Config, DatabaseConfig};
+use wt_analytics::error::WTError;
+use wt_analytics::jobs::PeriodicJob;
+use wt_analytics::logging::{self, RequestLogger};
+use wt_analytics::metrics::{self, PoolMetrics};
+use wt_analytics::pages::{self, Pages};
+use wt_analytics::rate_limit::{RateLimit, RateLimiter};
+use wt_analytics::readers::ReaderSalt;
+use wt_analytics::response_cache::ResponseCache;
+use wt_analytics::visit_buffer::{Flush, VisitBuffer};
+use wt_analytics::{api, AppState, DbConnection};
+use wt_analytics::{retention, rollup};
 
-embed_migrations!();
+const STARTUP_RETRY_INTERVAL: Duration = Duration::from_secs(2);
 
-pub type DbConnection = PgConnection;
+fn exit_on_error<T, E: Display>(result: Result<T, E>, message: &str) -> T {
+    result.unwrap_or_else(|error| {
+        tracing::error!(error = %error, "{}", message);
+        std::process::exit(1);
+    })
+}
 
-struct AppState {
-    db_pool: Pool<ConnectionManager<DbConnection>>,
+/// Waits until a connection can be established, so that the server can be started alongside the
+/// database instead of crashing while it is still booting. Blocks the thread, which is fine as long
+/// as it runs before anything else is started on the runtime.
+fn wait_for_database(config: &DatabaseConfig, url: &str) -> ConnectionResult<()> {
+    let deadline = Instant::now() + Duration::from_secs(config.startup_retry_seconds);
+    loop {
+        match DbConnection::establish(url) {
+            Ok(_) => return Ok(()),
+            Err(error) if Instant::now() < deadline => {
+                tracing::warn!(error = %error, "Database unavailable, retrying.");
+                std::thread::sleep(STARTUP_RETRY_INTERVAL);
+            }
+            Err(error) => return Err(error),
+        }
+    }
 }
 
 #[actix_rt::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    let manager = ConnectionManager::<DbConnection>::new(database_url);
-    let db_pool = Pool::new(manager).expect("Failed to create pool.");
-    embedded_migrations::run(
-        &db_pool
-            .get()
-            .expect("Failed to obtain connection for migration."),
+    let config = Config::load().unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    });
+    logging::init(&config.log);
+    metrics::register();
+    exit_on_error(
+        wait_for_database(&config.database, config.database_url()),
+        "Failed to connect to the database.",
+    );
+    let manager = ConnectionManager::<DbConnection>::new(config.database_url());
+    let db_pool = exit_on_error(
+        Pool::builder()
+            .max_size(config.database.pool_size)
+            .min_idle(config.database.min_idle)
+            .connection_timeout(Duration::from_secs(
+                config.database.connection_timeout_seconds,
+            ))
+            .event_handler(Box::new(PoolMetrics))
+            .build(manager),
+        "Failed to create pool.",
+    );
+    let connection = exit_on_error(db_pool.get(), "Failed to obtain connection for migration.");
+    exit_on_error(
+        wt_analytics::run_migrations(&connection),
+        "Migration failed.",
+    );
+    let stored_patterns = exit_on_error(
+        pages::load_stored_patterns(&connection),
+        "Failed to load page patterns.",
+    );
+    drop(connection);
+    let pages = Arc::new(exit_on_error(
+        Pages::load(&config.pages, &stored_patterns),
+        "Failed to load the page registry.",
+    ));
+    PeriodicJob::new(
+        "rollup",
+        db_pool.clone(),
+        Duration::from_secs(config.analytics.rollup_interval_seconds),
+        rollup::roll_up_job,
     )
-    .expect("Migration failed.");
-    HttpServer::new(move || {
-        let cors = Cors::default()
-            // .allowed_origin("http://127.0.0.1:2333")
-            // .allowed_origin("http://localhost:2333")
-            .allowed_origin("https://wt.tepis.me")
-            .allowed_origin("https://wt.bgme.bid")
-            .allowed_origin("https://wt.bgme.me")
-            .allowed_origin("https://rbq.desi")
-            .allowed_origin("https://wt.0w0.bid")
-            .allowed_origin("https://wt.umwings.com")
-            .allowed_origin("https://wt.innocent.love")
-            .allowed_origin("https://wt.mirror.0w0.bid")
-            .allowed_origin("http://rintepis.gitgud.site")
+    .start();
+    let reloaded_pages = pages.clone();
+    PeriodicJob::new(
+        "page patterns",
+        db_pool.clone(),
+        Duration::from_secs(config.pages.reload_interval_seconds),
+        move |connection| pages::reload_job(connection, &reloaded_pages),
+    )
+    .start();
+    if config.retention.visit_days.is_some() || config.retention.hourly_rollup_days.is_some() {
+        let retention = config.retention.clone();
+        PeriodicJob::new(
+            "retention",
+            db_pool.clone(),
+            Duration::from_secs(retention.interval_seconds),
+            move |connection| retention::retention_job(connection, &retention),
+        )
+        .start();
+    }
+    let funnel_chapters = exit_on_error(
+        config.analytics.load_funnel_chapters(),
+        "Failed to load the funnel chapters.",
+    );
+    let rate_limiter = Arc::new(RateLimiter::new(&config.rate_limit));
+    let bot_filter =
+        BotFilter::new(&config.bot_filter).expect("bot_filter is checked during validation");
+    let response_cache = ResponseCache::new(&config.cache);
+    let bind_addresses = config.server.bind_addresses.clone();
+    let workers = config.server.workers;
+    let shutdown_timeout = config.server.shutdown_timeout_seconds;
+    let metrics_bind_address = config.metrics.bind_address.clone();
+    // Without a separate address, metrics are only served publicly behind a token.
+    let public_metrics = metrics_bind_address.is_none() && config.metrics.bearer_token.is_some();
+    let visit_buffer = VisitBuffer::new(db_pool.clone(), &config.analytics, &config.pages).start();
+    let reader_salt = config.analytics.unique_readers.then(ReaderSalt::default);
+    let state = web::Data::new(AppState {
+        db_pool,
+        config,
+        rate_limiter,
+        bot_filter,
+        response_cache,
+        pages,
+        visit_buffer: visit_buffer.clone(),
+        reader_salt,
+        funnel_chapters,
+    });
+    if let Some(address) = metrics_bind_address {
+        let state = state.clone();
+        let metrics_server = HttpServer::new(move || {
+            App::new()
+                .app_data(state.clone())
+                .service(metrics::get_service())
+        })
+        .workers(1)
+        .shutdown_timeout(shutdown_timeout)
+        .bind(address)?
+        .run();
+        actix_rt::spawn(metrics_server);
+    }
+    let mut server = HttpServer::new(move || {
+        let cors = state
+            .config
+            .server
+            .allowed_origins
+            .iter()
+            .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
             .allowed_methods(vec!["GET", "POST"])
             .allowed_header("Content-Type")
             .max_age(3600);
         App::new()
-            .app_data(actix_web::web::Data::new(AppState {
-                db_pool: db_pool.clone(),
-            }))
+            .app_data(
+                web::JsonConfig::default()
+                    .error_handler(|error, _| WTError::Validation(error.to_string()).into()),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(|error, _| WTError::Validation(error.to_string()).into()),
+            )
+            .app_data(state.clone())
+            .wrap(RateLimit::new(state.rate_limiter.clone()))
             .wrap(cors)
+            .wrap(RequestLogger)
             .service(api::analytics::get_service())
             .service(api::user::get_service())
             .service(api::comment::get_service())
             .service(api::event::get_service())
-    })
-    .bind("127.0.0.1:8088")?
-    .run()
-    .await
+            .service(api::admin::get_service())
+            .service(api::notification::get_service())
+            .service(api::health::get_service())
+            .configure(|service_config| {
+                if public_metrics {
+                    service_config.service(metrics::get_service());
+                }
+            })
+    });
+    // Actix stops accepting connections on SIGTERM, SIGINT or SIGQUIT and waits up to the shutdown
+    // timeout for in-flight requests, so deploys do not drop comments being posted.
+    server = server.shutdown_timeout(shutdown_timeout);
+    if let Some(workers) = workers {
+        server = server.workers(workers);
+    }
+    for address in bind_addresses {
+        server = server.bind(address)?;
+    }
+    server.run().await?;
+    // The server no longer takes requests, so this catches every buffered visit.
+    if let Err(error) = visit_buffer.send(Flush).await {
+        tracing::error!(error = %error, "Failed to flush buffered visits.");
+    }
+    tracing::info!("Server stopped.");
+    Ok(())
 }
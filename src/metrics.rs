@@ -0,0 +1,219 @@
+use actix_web::dev::HttpServiceFactory;
+use actix_web::http::header;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder};
+use diesel::r2d2::event::{CheckoutEvent, TimeoutEvent};
+use diesel::r2d2::HandleEvent;
+use prometheus::{
+    register_histogram, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, Encoder, Histogram, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, TextEncoder,
+};
+
+use crate::AppState;
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "wt_http_requests_total",
+        "Handled requests by route pattern, method and status.",
+        &["route", "method", "status"]
+    )
+    .unwrap();
+    static ref HTTP_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "wt_http_request_duration_seconds",
+        "Time spent handling requests by route pattern.",
+        &["route"]
+    )
+    .unwrap();
+    static ref DB_CALL_DURATION: HistogramVec = register_histogram_vec!(
+        "wt_db_call_duration_seconds",
+        "Time spent in blocking database calls by source location.",
+        &["location"]
+    )
+    .unwrap();
+    static ref DB_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "wt_db_pool_connections",
+        "Database pool connections by state, sampled when scraped.",
+        &["state"]
+    )
+    .unwrap();
+    static ref DB_POOL_WAIT: Histogram = register_histogram!(
+        "wt_db_pool_wait_seconds",
+        "Time spent waiting to check out a database connection."
+    )
+    .unwrap();
+    static ref DB_POOL_TIMEOUTS: IntCounter = register_int_counter!(
+        "wt_db_pool_timeouts_total",
+        "Connection checkouts that timed out."
+    )
+    .unwrap();
+    pub static ref COMMENTS_SENT: IntCounter =
+        register_int_counter!("wt_comments_sent_total", "Comments sent.").unwrap();
+    pub static ref COMMENTS_DELETED: IntCounter = register_int_counter!(
+        "wt_comments_deleted_total",
+        "Comments deleted by their authors or moderators."
+    )
+    .unwrap();
+    pub static ref VISITS_RECORDED: IntCounter =
+        register_int_counter!("wt_visits_recorded_total", "Chapter visits recorded.").unwrap();
+    pub static ref VISIT_BUFFER_DEPTH: IntGauge = register_int_gauge!(
+        "wt_visit_buffer_depth",
+        "Visits accepted but not yet written to the database."
+    )
+    .unwrap();
+    pub static ref VISIT_FLUSH_DURATION: Histogram = register_histogram!(
+        "wt_visit_flush_duration_seconds",
+        "Time spent writing buffered visits to the database."
+    )
+    .unwrap();
+    pub static ref VISITS_DROPPED: IntCounter = register_int_counter!(
+        "wt_visits_dropped_total",
+        "Buffered visits given up on after the database stayed unavailable."
+    )
+    .unwrap();
+    pub static ref VISITS_FILTERED: IntCounterVec = register_int_counter_vec!(
+        "wt_visits_filtered_total",
+        "Visits not counted because the bot filter matched them, by reason.",
+        &["reason"]
+    )
+    .unwrap();
+    pub static ref RESPONSE_CACHE_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "wt_response_cache_lookups_total",
+        "Response cache lookups by result, hit or miss.",
+        &["result"]
+    )
+    .unwrap();
+}
+
+/// Registers every metric up front so that scrapes include them before their first update.
+pub fn register() {
+    lazy_static::initialize(&HTTP_REQUESTS);
+    lazy_static::initialize(&HTTP_REQUEST_DURATION);
+    lazy_static::initialize(&DB_CALL_DURATION);
+    lazy_static::initialize(&DB_POOL_CONNECTIONS);
+    lazy_static::initialize(&DB_POOL_WAIT);
+    lazy_static::initialize(&DB_POOL_TIMEOUTS);
+    lazy_static::initialize(&COMMENTS_SENT);
+    lazy_static::initialize(&COMMENTS_DELETED);
+    lazy_static::initialize(&VISITS_RECORDED);
+    lazy_static::initialize(&VISIT_BUFFER_DEPTH);
+    lazy_static::initialize(&VISIT_FLUSH_DURATION);
+    lazy_static::initialize(&VISITS_DROPPED);
+    lazy_static::initialize(&VISITS_FILTERED);
+    lazy_static::initialize(&RESPONSE_CACHE_LOOKUPS);
+}
+
+pub fn observe_request(route: &str, method: &str, status: u16, seconds: f64) {
+    HTTP_REQUESTS
+        .with_label_values(&[route, method, &status.to_string()])
+        .inc();
+    HTTP_REQUEST_DURATION
+        .with_label_values(&[route])
+        .observe(seconds);
+}
+
+pub fn observe_db_call(location: &str, seconds: f64) {
+    DB_CALL_DURATION
+        .with_label_values(&[location])
+        .observe(seconds);
+}
+
+/// Records checkout waits and timeouts of the database pool.
+#[derive(Debug)]
+pub struct PoolMetrics;
+
+impl HandleEvent for PoolMetrics {
+    fn handle_checkout(&self, event: CheckoutEvent) {
+        DB_POOL_WAIT.observe(event.duration().as_secs_f64());
+    }
+
+    fn handle_timeout(&self, _event: TimeoutEvent) {
+        DB_POOL_TIMEOUTS.inc();
+    }
+}
+
+fn is_authorized(request: &HttpRequest, bearer_token: &Option<String>) -> bool {
+    let bearer_token = match bearer_token {
+        Some(bearer_token) => bearer_token,
+        None => return true,
+    };
+    match request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.as_bytes().strip_prefix(b"Bearer "))
+    {
+        Some(token) => constant_time_eq(token, bearer_token.as_bytes()),
+        None => false,
+    }
+}
+
+/// Compares every byte regardless of where the first difference is, so that response times do not
+/// tell how much of a guessed token is right. Only the length may leak.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len()
+        && a.iter()
+            .zip(b)
+            .fold(0, |difference, (x, y)| difference | (x ^ y))
+            == 0
+}
+
+#[get("/metrics")]
+async fn metrics_handler(state: web::Data<AppState>, request: HttpRequest) -> impl Responder {
+    if !is_authorized(&request, &state.config.metrics.bearer_token) {
+        return HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .finish();
+    }
+    let pool_state = state.db_pool.state();
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["idle"])
+        .set(pool_state.idle_connections.into());
+    DB_POOL_CONNECTIONS
+        .with_label_values(&["in_use"])
+        .set((pool_state.connections - pool_state.idle_connections).into());
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut body) {
+        Ok(()) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(body),
+        Err(error) => {
+            tracing::error!(error = %error, "failed to encode metrics");
+            HttpResponse::InternalServerError().finish()
+        }
+    }
+}
+
+pub fn get_service() -> impl HttpServiceFactory {
+    metrics_handler
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn authorized(authorization: Option<&str>, bearer_token: Option<&str>) -> bool {
+        let mut request = TestRequest::default();
+        if let Some(authorization) = authorization {
+            request = request.insert_header((header::AUTHORIZATION, authorization));
+        }
+        is_authorized(&request.to_http_request(), &bearer_token.map(str::to_owned))
+    }
+
+    #[test]
+    fn bearer_token_must_match_exactly() {
+        let token = Some("0123456789abcdef");
+        assert!(authorized(Some("Bearer 0123456789abcdef"), token));
+        assert!(!authorized(Some("Bearer 0123456789abcdee"), token));
+        assert!(!authorized(Some("Bearer 0123456789abcdef0"), token));
+        assert!(!authorized(Some("Bearer 0123456789abcde"), token));
+        assert!(!authorized(Some("Basic 0123456789abcdef"), token));
+        assert!(!authorized(None, token));
+    }
+
+    #[test]
+    fn no_token_is_needed_when_none_is_configured() {
+        assert!(authorized(None, None));
+    }
+}
@@ -1,6 +1,9 @@
+use crate::schema::chapter_aliases;
 use crate::schema::chapters;
+use crate::schema::comment_revisions;
 use crate::schema::comments;
 use crate::schema::mentions;
+use crate::schema::moderation_logs;
 use crate::schema::users;
 use crate::schema::visits;
 use crate::schema::wtcup_2020_votes;
@@ -12,6 +15,15 @@ pub struct Chapter {
     pub visit_count: i64,
 }
 
+/// An old path of a chapter, e.g. from before the page was renamed.
+#[derive(Identifiable, Queryable)]
+#[table_name = "chapter_aliases"]
+#[primary_key(relative_path)]
+pub struct ChapterAlias {
+    pub relative_path: String,
+    pub chapter_id: i32,
+}
+
 #[derive(Identifiable, Queryable)]
 pub struct Visit {
     pub id: i64,
@@ -28,17 +40,27 @@ pub struct Comment {
     pub deleted: bool,
     pub create_timestamp: i64,
     pub update_timestamp: i64,
+    pub parent_comment_id: Option<i64>,
 }
 
 joinable!(comments -> users (user_id));
 joinable!(comments -> chapters (chapter_id));
 
+#[derive(Identifiable, Queryable)]
+pub struct CommentRevision {
+    pub id: i64,
+    pub comment_id: i64,
+    pub content: String,
+    pub timestamp: i64,
+}
+
 #[derive(Identifiable, Queryable)]
 pub struct Mention {
     pub id: i64,
     pub from_comment_id: i64,
     pub mentioned_user_id: i64,
     pub timestamp: i64,
+    pub read: bool,
 }
 
 #[derive(Identifiable, Queryable, AsChangeset)]
@@ -51,7 +73,24 @@ pub struct User {
     pub user_name: String,
     pub display_name: String,
     pub disabled: bool,
+    /// No longer written since mentions carry their own read state; kept so that the migration
+    /// backfilling that state from it can be rolled back and applied again.
     pub last_checked_mentions_timestamp: i64,
+    pub role: i16,
+}
+
+#[derive(Identifiable, Queryable)]
+pub struct ModerationLog {
+    pub id: i64,
+    /// `None` for actions taken through wt_admin.
+    pub moderator_id: Option<i64>,
+    pub action: i16,
+    pub target_comment_id: Option<i64>,
+    pub target_user_id: Option<i64>,
+    pub timestamp: i64,
+    pub role: Option<i16>,
+    pub from_chapter_path: Option<String>,
+    pub into_chapter_path: Option<String>,
 }
 
 #[derive(Identifiable, Queryable)]
@@ -0,0 +1,249 @@
+use std::fs;
+use std::io;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use diesel::prelude::*;
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+
+use crate::config::PagesConfig;
+use crate::error::WTError;
+use crate::schema::page_patterns;
+use crate::DbConnection;
+
+/// Marks a pattern as a regular expression instead of a glob.
+pub const REGEX_PREFIX: &str = "re:";
+
+/// What happens to paths that match no pattern of the registry and have no chapter yet.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownPages {
+    /// Chapters are created for any path; the registry is not consulted.
+    Allow,
+    /// Visits are refused and comments rejected.
+    Reject,
+    /// Visits are only counted in `quarantined_pages` for review; comments are rejected.
+    Quarantine,
+}
+
+impl FromStr for UnknownPages {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "allow" => Ok(UnknownPages::Allow),
+            "reject" => Ok(UnknownPages::Reject),
+            "quarantine" => Ok(UnknownPages::Quarantine),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Translates a pattern into an anchored regular expression. In globs, `*` and `?` do not match
+/// `/` while `**` matches anything.
+fn pattern_to_regex(pattern: &str) -> Result<String, regex::Error> {
+    if let Some(regex) = pattern.strip_prefix(REGEX_PREFIX) {
+        // Checked on its own first, so that unbalanced parentheses such as in `x)|(?:.*` cannot
+        // close the group and escape the anchors.
+        Regex::new(regex)?;
+        return Ok(format!("^(?:{})$", regex));
+    }
+    let mut regex = String::from("^");
+    let mut chars = pattern.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            ch => regex.push_str(&regex::escape(ch.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    Ok(regex)
+}
+
+pub struct PageRegistry {
+    unknown: UnknownPages,
+    patterns: RegexSet,
+}
+
+impl PageRegistry {
+    pub fn new(unknown: UnknownPages, patterns: &[String]) -> Result<PageRegistry, regex::Error> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| pattern_to_regex(pattern))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(PageRegistry {
+            unknown,
+            patterns: RegexSet::new(patterns)?,
+        })
+    }
+
+    pub fn unknown(&self) -> UnknownPages {
+        self.unknown
+    }
+
+    /// Whether a chapter may be created for `relative_path`.
+    pub fn is_known(&self, relative_path: &str) -> bool {
+        self.unknown == UnknownPages::Allow || self.patterns.is_match(relative_path)
+    }
+}
+
+/// Reads a manifest with one pattern per line. Blank lines and lines starting with `#` are
+/// skipped.
+pub fn read_manifest(path: &str) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect())
+}
+
+pub fn load_stored_patterns(connection: &DbConnection) -> QueryResult<Vec<String>> {
+    page_patterns::table
+        .select(page_patterns::pattern)
+        .order_by(page_patterns::pattern)
+        .load(connection)
+}
+
+/// The page registry, combining the patterns from the configuration and manifest with those
+/// stored through the admin endpoints.
+pub struct Pages {
+    unknown: UnknownPages,
+    configured_patterns: Vec<String>,
+    registry: RwLock<Arc<PageRegistry>>,
+}
+
+impl Pages {
+    pub fn load(config: &PagesConfig, stored_patterns: &[String]) -> Result<Pages, String> {
+        let mut configured_patterns = config.patterns.clone();
+        if let Some(manifest) = &config.manifest {
+            configured_patterns.extend(
+                read_manifest(manifest)
+                    .map_err(|error| format!("Failed to read {}: {}", manifest, error))?,
+            );
+        }
+        let registry = Self::build(config.unknown, &configured_patterns, stored_patterns)
+            .map_err(|error| format!("Invalid page pattern: {}", error))?;
+        Ok(Pages {
+            unknown: config.unknown,
+            configured_patterns,
+            registry: RwLock::new(Arc::new(registry)),
+        })
+    }
+
+    fn build(
+        unknown: UnknownPages,
+        configured_patterns: &[String],
+        stored_patterns: &[String],
+    ) -> Result<PageRegistry, regex::Error> {
+        let patterns: Vec<String> = configured_patterns
+            .iter()
+            .chain(stored_patterns)
+            .cloned()
+            .collect();
+        PageRegistry::new(unknown, &patterns)
+    }
+
+    pub fn configured_patterns(&self) -> &[String] {
+        &self.configured_patterns
+    }
+
+    pub fn current(&self) -> Arc<PageRegistry> {
+        self.registry.read().unwrap().clone()
+    }
+
+    /// Builds the registry for new stored patterns without installing it, so that they can be
+    /// checked before they are stored.
+    pub fn with_stored_patterns(
+        &self,
+        stored_patterns: &[String],
+    ) -> Result<PageRegistry, regex::Error> {
+        Self::build(self.unknown, &self.configured_patterns, stored_patterns)
+    }
+
+    pub fn replace(&self, registry: PageRegistry) {
+        *self.registry.write().unwrap() = Arc::new(registry);
+    }
+}
+
+/// The job run by `PeriodicJob`, picking up patterns stored through other instances.
+pub fn reload_job(connection: &DbConnection, pages: &Pages) -> Result<(), WTError> {
+    let stored_patterns = load_stored_patterns(connection)?;
+    let registry = pages
+        .with_stored_patterns(&stored_patterns)
+        .map_err(|error| WTError::InternalError(Box::new(error)))?;
+    pages.replace(registry);
+    Ok(())
+}
+
+/// Checks that a pattern compiles on its own, for validating admin input.
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    pattern_to_regex(pattern)
+        .and_then(|regex| RegexSet::new([regex]))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry(patterns: &[&str]) -> PageRegistry {
+        let patterns: Vec<String> = patterns.iter().map(|pattern| pattern.to_string()).collect();
+        PageRegistry::new(UnknownPages::Reject, &patterns).unwrap()
+    }
+
+    #[test]
+    fn single_star_and_question_mark_stay_within_a_segment() {
+        let registry = registry(&["chapters/*", "notes/?.md"]);
+        assert!(registry.is_known("chapters/one"));
+        assert!(registry.is_known("chapters/"));
+        assert!(!registry.is_known("chapters/one/two"));
+        assert!(registry.is_known("notes/a.md"));
+        assert!(!registry.is_known("notes/ab.md"));
+        assert!(!registry.is_known("notes//.md"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        let registry = registry(&["wtcup/**"]);
+        assert!(registry.is_known("wtcup/2022/one"));
+        assert!(!registry.is_known("other/wtcup/one"));
+    }
+
+    #[test]
+    fn globs_are_anchored_and_match_other_characters_literally() {
+        let registry = registry(&["a.b+c"]);
+        assert!(registry.is_known("a.b+c"));
+        assert!(!registry.is_known("axb+c"));
+        assert!(!registry.is_known("a.b+cd"));
+    }
+
+    #[test]
+    fn prefixed_patterns_are_anchored_regular_expressions() {
+        let registry = registry(&["re:wtcup-20(20|21)/.+"]);
+        assert!(registry.is_known("wtcup-2021/one"));
+        assert!(!registry.is_known("wtcup-2022/one"));
+        assert!(!registry.is_known("old/wtcup-2021/one"));
+        assert!(!is_valid_pattern("re:("));
+    }
+
+    #[test]
+    fn prefixed_patterns_cannot_escape_the_anchors() {
+        let pattern = "re:x)|(?:.*";
+        assert!(!is_valid_pattern(pattern));
+        assert!(PageRegistry::new(UnknownPages::Reject, &[pattern.to_owned()]).is_err());
+        assert!(is_valid_pattern("re:(a|b)/c"));
+    }
+
+    #[test]
+    fn everything_is_known_when_unknown_pages_are_allowed() {
+        let registry = PageRegistry::new(UnknownPages::Allow, &[]).unwrap();
+        assert!(registry.is_known("anything/at/all"));
+    }
+}
@@ -0,0 +1,453 @@
+use std::collections::{BTreeSet, HashMap};
+use std::future::{ready, Future, Ready};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::HeaderMap;
+use actix_web::Error;
+use rand::Rng;
+use serde::Deserialize;
+
+use crate::config::RateLimitConfig;
+use crate::error::WTError;
+
+/// Routes whose handlers check the limit themselves, keyed by the authenticated user id. Every
+/// other configured route is limited by the middleware, keyed by client address.
+pub const USER_KEYED_ROUTES: &[&str] = &["/comment/send", "/comment/edit"];
+// The buckets updated longest ago are dropped once this many are held.
+const MAX_BUCKETS_PER_ROUTE: usize = 100_000;
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct RouteLimit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+#[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum RateLimitKey {
+    User(i64),
+    /// A salted hash of the client address. The salt only lives in memory, so neither the address
+    /// nor anything that could be traced back to it is ever persisted.
+    Client(u64),
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct Buckets {
+    by_key: HashMap<RateLimitKey, Bucket>,
+    /// The same buckets ordered by when they were last updated, to find the one to evict.
+    by_age: BTreeSet<(Instant, RateLimitKey)>,
+}
+
+struct RouteBuckets {
+    limit: RouteLimit,
+    buckets: Mutex<Buckets>,
+}
+
+impl RouteBuckets {
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.limit.per_minute as f64 / 60.0)
+            .min(self.limit.burst as f64);
+        bucket.updated = now;
+    }
+
+    fn take(&self, key: RateLimitKey) -> bool {
+        let now = Instant::now();
+        let mut guard = self.buckets.lock().unwrap();
+        let buckets = &mut *guard;
+        match buckets.by_key.get(&key) {
+            Some(bucket) => {
+                buckets.by_age.remove(&(bucket.updated, key));
+            }
+            None if buckets.by_key.len() >= MAX_BUCKETS_PER_ROUTE => {
+                // Unless a client rotates through this many keys faster than its bucket refills,
+                // the evicted bucket has long been full again.
+                if let Some((_, oldest)) = buckets.by_age.pop_first() {
+                    buckets.by_key.remove(&oldest);
+                }
+            }
+            None => {}
+        }
+        let bucket = buckets.by_key.entry(key).or_insert(Bucket {
+            tokens: self.limit.burst as f64,
+            updated: now,
+        });
+        self.refill(bucket, now);
+        buckets.by_age.insert((now, key));
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// An address or a CIDR network such as `192.0.2.0/24` or `2001:db8::/32`.
+pub struct Network {
+    address: IpAddr,
+    prefix: u32,
+}
+
+impl Network {
+    pub fn parse(value: &str) -> Option<Network> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address: IpAddr = address.parse().ok()?;
+        let bits = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().ok().filter(|prefix| *prefix <= bits)?,
+            None => bits,
+        };
+        Some(Network { address, prefix })
+    }
+
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.address, address.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Token buckets for every rate limited route, shared by all workers.
+pub struct RateLimiter {
+    enabled: bool,
+    trust_proxy_headers: bool,
+    trusted_proxies: Vec<Network>,
+    salt: u64,
+    routes: HashMap<String, RouteBuckets>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> RateLimiter {
+        RateLimiter {
+            enabled: config.enabled,
+            trust_proxy_headers: config.trust_proxy_headers,
+            trusted_proxies: config
+                .trusted_proxies
+                .iter()
+                .map(|proxy| {
+                    Network::parse(proxy).expect("trusted_proxies is checked during validation")
+                })
+                .collect(),
+            salt: rand::thread_rng().gen(),
+            routes: config
+                .routes
+                .iter()
+                .map(|(route, limit)| {
+                    (
+                        route.clone(),
+                        RouteBuckets {
+                            limit: *limit,
+                            buckets: Mutex::new(Buckets::default()),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    /// Takes a token for `key` on `route`, returning `false` if the bucket is empty. Routes
+    /// without a configured limit are never limited.
+    pub fn check(&self, route: &str, key: RateLimitKey) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        match self.routes.get(route) {
+            Some(route) => route.take(key),
+            None => true,
+        }
+    }
+
+    fn is_trusted_proxy(&self, address: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(address))
+    }
+
+    /// The client's IP address, without the port so that all of a client's connections agree.
+    /// Behind trusted proxies, `X-Forwarded-For` is read from the right, skipping the hops that
+    /// trusted proxies appended, since every entry before those is whatever the client sent.
+    pub fn client_host(&self, peer_addr: Option<SocketAddr>, headers: &HeaderMap) -> String {
+        let mut address = match peer_addr {
+            Some(peer_addr) => peer_addr.ip(),
+            None => return String::new(),
+        };
+        if self.trust_proxy_headers {
+            let hops = headers
+                .get_all("x-forwarded-for")
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .collect::<Vec<_>>();
+            for hop in hops.into_iter().rev() {
+                if !self.is_trusted_proxy(address) {
+                    break;
+                }
+                let hop = hop.trim();
+                address = match hop.parse::<IpAddr>() {
+                    Ok(hop) => hop,
+                    Err(_) => match hop.parse::<SocketAddr>() {
+                        Ok(hop) => hop.ip(),
+                        Err(_) => break,
+                    },
+                };
+            }
+        }
+        address.to_canonical().to_string()
+    }
+
+    fn limits_clients_on(&self, route: &str) -> bool {
+        self.enabled && !USER_KEYED_ROUTES.contains(&route) && self.routes.contains_key(route)
+    }
+
+    fn client_key(&self, request: &ServiceRequest) -> RateLimitKey {
+        let host = self.client_host(request.peer_addr(), request.headers());
+        let mut salted = self.salt.to_le_bytes().to_vec();
+        salted.extend_from_slice(host.as_bytes());
+        RateLimitKey::Client(seahash::hash(&salted))
+    }
+}
+
+/// Middleware limiting anonymous routes by client address.
+pub struct RateLimit {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimit {
+    pub fn new(limiter: Arc<RateLimiter>) -> RateLimit {
+        RateLimit { limiter }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            limiter: self.limiter.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        if self.limiter.limits_clients_on(request.path()) {
+            let key = self.limiter.client_key(&request);
+            if !self.limiter.check(request.path(), key) {
+                return Box::pin(ready(Ok(request
+                    .error_response(WTError::RateLimited)
+                    .map_into_right_body())));
+            }
+        }
+        let service = self.service.clone();
+        Box::pin(async move { Ok(service.call(request).await?.map_into_left_body()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::http::header::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    fn network(value: &str) -> Network {
+        Network::parse(value).unwrap()
+    }
+
+    fn address(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn route(burst: u32, per_minute: u32) -> RouteBuckets {
+        RouteBuckets {
+            limit: RouteLimit { burst, per_minute },
+            buckets: Mutex::new(Buckets::default()),
+        }
+    }
+
+    #[test]
+    fn network_contains_addresses_within_its_prefix() {
+        let network = network("192.0.2.0/24");
+        assert!(network.contains(address("192.0.2.1")));
+        assert!(network.contains(address("192.0.2.255")));
+        assert!(!network.contains(address("192.0.3.1")));
+        assert!(!network.contains(address("2001:db8::1")));
+    }
+
+    #[test]
+    fn network_matches_ipv4_mapped_ipv6_addresses() {
+        assert!(network("192.0.2.0/24").contains(address("::ffff:192.0.2.7")));
+    }
+
+    #[test]
+    fn network_handles_ipv6_and_edge_prefixes() {
+        assert!(network("2001:db8::/32").contains(address("2001:db8:ffff::1")));
+        assert!(!network("2001:db8::/32").contains(address("2001:db9::1")));
+        assert!(network("0.0.0.0/0").contains(address("203.0.113.9")));
+        assert!(network("203.0.113.9").contains(address("203.0.113.9")));
+        assert!(!network("203.0.113.9").contains(address("203.0.113.8")));
+    }
+
+    #[test]
+    fn network_parse_rejects_invalid_input() {
+        assert!(Network::parse("192.0.2.0/33").is_none());
+        assert!(Network::parse("2001:db8::/129").is_none());
+        assert!(Network::parse("example.com").is_none());
+        assert!(Network::parse("192.0.2.0/").is_none());
+    }
+
+    #[test]
+    fn refill_adds_tokens_over_time_up_to_the_burst() {
+        let route = route(10, 60);
+        let now = Instant::now();
+        let mut bucket = Bucket {
+            tokens: 0.0,
+            updated: now,
+        };
+        route.refill(&mut bucket, now + Duration::from_secs(3));
+        assert!((bucket.tokens - 3.0).abs() < 1e-9);
+        route.refill(&mut bucket, now + Duration::from_secs(60));
+        assert_eq!(bucket.tokens, 10.0);
+    }
+
+    #[test]
+    fn take_empties_the_bucket_per_key() {
+        let route = route(2, 1);
+        let (first, second) = (RateLimitKey::User(1), RateLimitKey::User(2));
+        assert!(route.take(first));
+        assert!(route.take(first));
+        assert!(!route.take(first));
+        assert!(route.take(second));
+    }
+
+    #[test]
+    fn check_only_limits_configured_routes_when_enabled() {
+        let mut config = RateLimitConfig::default();
+        config.routes.insert(
+            "/test".to_owned(),
+            RouteLimit {
+                burst: 1,
+                per_minute: 1,
+            },
+        );
+        let limiter = RateLimiter::new(&config);
+        assert!(limiter.check("/test", RateLimitKey::User(1)));
+        assert!(!limiter.check("/test", RateLimitKey::User(1)));
+        assert!(limiter.check("/unlimited", RateLimitKey::User(1)));
+        config.enabled = false;
+        let limiter = RateLimiter::new(&config);
+        assert!(limiter.check("/test", RateLimitKey::User(1)));
+        assert!(limiter.check("/test", RateLimitKey::User(1)));
+    }
+    #[test]
+    fn take_evicts_the_least_recently_updated_bucket() {
+        let route = route(1, 1);
+        for key in 0..MAX_BUCKETS_PER_ROUTE as u64 {
+            route.take(RateLimitKey::Client(key));
+        }
+        route.take(RateLimitKey::Client(0));
+        route.take(RateLimitKey::Client(u64::MAX));
+        let buckets = route.buckets.lock().unwrap();
+        assert_eq!(buckets.by_key.len(), MAX_BUCKETS_PER_ROUTE);
+        assert_eq!(buckets.by_age.len(), MAX_BUCKETS_PER_ROUTE);
+        assert!(buckets.by_key.contains_key(&RateLimitKey::Client(0)));
+        assert!(!buckets.by_key.contains_key(&RateLimitKey::Client(1)));
+    }
+
+    fn client_host(trusted_proxies: &[&str], peer: &str, forwarded_for: &[&str]) -> String {
+        let config = RateLimitConfig {
+            trust_proxy_headers: !trusted_proxies.is_empty(),
+            trusted_proxies: trusted_proxies
+                .iter()
+                .map(|proxy| proxy.to_string())
+                .collect(),
+            ..RateLimitConfig::default()
+        };
+        let mut headers = HeaderMap::new();
+        for value in forwarded_for {
+            headers.append(
+                HeaderName::from_static("x-forwarded-for"),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        RateLimiter::new(&config).client_host(Some(peer.parse().unwrap()), &headers)
+    }
+
+    #[test]
+    fn client_host_ignores_forwarded_for_by_default() {
+        assert_eq!(
+            client_host(&[], "198.51.100.1:1234", &["203.0.113.5"]),
+            "198.51.100.1"
+        );
+    }
+
+    #[test]
+    fn client_host_takes_the_hop_before_the_trusted_proxies() {
+        let proxies = ["10.0.0.0/8"];
+        let forwarded_for = ["203.0.113.5, 198.51.100.7", "10.0.0.2"];
+        assert_eq!(
+            client_host(&proxies, "10.0.0.1:1234", &forwarded_for),
+            "198.51.100.7"
+        );
+    }
+
+    #[test]
+    fn client_host_only_trusts_forwarded_for_from_proxies() {
+        let proxies = ["10.0.0.1"];
+        assert_eq!(
+            client_host(&proxies, "198.51.100.1:1234", &["203.0.113.5"]),
+            "198.51.100.1"
+        );
+        assert_eq!(
+            client_host(&proxies, "10.0.0.1:1234", &["not an address"]),
+            "10.0.0.1"
+        );
+    }
+}
@@ -0,0 +1,227 @@
+use std::sync::Mutex;
+
+use diesel::insert_into;
+use diesel::prelude::*;
+use rand::Rng;
+
+use crate::rollup::{self, DAY_MILLISECONDS};
+use crate::schema::{reader_sketches, site_reader_sketches};
+use crate::DbConnection;
+
+/// 2^10 one-byte registers: 1 KiB per chapter and day for a standard error of about 3%.
+const PRECISION: u32 = 10;
+const REGISTERS: usize = 1 << PRECISION;
+
+/// A HyperLogLog sketch of the distinct reader hashes seen on one day. Only the highest rank per
+/// register is kept, which tells nothing about the readers themselves.
+#[derive(Clone)]
+pub struct Sketch {
+    registers: Vec<u8>,
+}
+
+impl Default for Sketch {
+    fn default() -> Self {
+        Sketch {
+            registers: vec![0; REGISTERS],
+        }
+    }
+}
+
+impl Sketch {
+    /// Reads a stored sketch. Anything of the wrong size is treated as empty.
+    pub fn from_bytes(registers: Vec<u8>) -> Self {
+        if registers.len() == REGISTERS {
+            Sketch { registers }
+        } else {
+            Sketch::default()
+        }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.registers
+    }
+
+    pub fn insert(&mut self, hash: u64) {
+        let index = (hash >> (64 - PRECISION)) as usize;
+        // The guard bit caps the rank for the remaining 54 bits.
+        let rank = ((hash << PRECISION) | (1 << (PRECISION - 1))).leading_zeros() as u8 + 1;
+        if rank > self.registers[index] {
+            self.registers[index] = rank;
+        }
+    }
+
+    pub fn merge(&mut self, other: &Sketch) {
+        for (register, other) in self.registers.iter_mut().zip(&other.registers) {
+            *register = (*register).max(*other);
+        }
+    }
+
+    pub fn estimate(&self) -> f64 {
+        let m = REGISTERS as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum: f64 = self
+            .registers
+            .iter()
+            .map(|register| 2f64.powi(-i32::from(*register)))
+            .sum();
+        let estimate = alpha * m * m / sum;
+        let zeros = self
+            .registers
+            .iter()
+            .filter(|register| **register == 0)
+            .count();
+        // Linear counting is more accurate while many registers are still empty.
+        if estimate <= 2.5 * m && zeros > 0 {
+            m * (m / zeros as f64).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+/// Hashes readers with a salt that is replaced at every UTC midnight and never leaves memory. Once
+/// a day is over, its hashes can neither be recomputed from a reader's address nor linked to the
+/// hashes of any other day.
+pub struct ReaderSalt {
+    current: Mutex<(i64, [u8; 16])>,
+}
+
+impl Default for ReaderSalt {
+    fn default() -> Self {
+        ReaderSalt {
+            current: Mutex::new((i64::MIN, [0; 16])),
+        }
+    }
+}
+
+impl ReaderSalt {
+    /// Returns the day the reader is counted on along with their salted hash.
+    pub fn hash(&self, timestamp: i64, host: &str, user_agent: &str) -> (i64, u64) {
+        let day = rollup::floor(timestamp, DAY_MILLISECONDS);
+        let mut current = self.current.lock().unwrap();
+        // Requests racing across midnight are counted on the new day rather than bringing the old
+        // salt back.
+        if day > current.0 {
+            *current = (day, rand::thread_rng().gen());
+        }
+        let mut salted = current.1.to_vec();
+        salted.extend_from_slice(host.as_bytes());
+        salted.push(0);
+        salted.extend_from_slice(user_agent.as_bytes());
+        (current.0, seahash::hash(&salted))
+    }
+}
+
+/// Merges `sketch` into the stored sketch of a chapter's day.
+pub fn merge_chapter_sketch(
+    connection: &DbConnection,
+    chapter_id: i32,
+    day: i64,
+    sketch: &Sketch,
+) -> QueryResult<()> {
+    // Inserting first gives the row to lock, so that concurrent merges do not overwrite each other.
+    insert_into(reader_sketches::table)
+        .values((
+            reader_sketches::chapter_id.eq(chapter_id),
+            reader_sketches::day_start.eq(day),
+            reader_sketches::registers.eq(Sketch::default().as_bytes()),
+        ))
+        .on_conflict_do_nothing()
+        .execute(connection)?;
+    let mut stored = Sketch::from_bytes(
+        reader_sketches::table
+            .find((chapter_id, day))
+            .select(reader_sketches::registers)
+            .for_update()
+            .first(connection)?,
+    );
+    stored.merge(sketch);
+    diesel::update(reader_sketches::table.find((chapter_id, day)))
+        .set(reader_sketches::registers.eq(stored.as_bytes()))
+        .execute(connection)?;
+    Ok(())
+}
+
+/// Like `merge_chapter_sketch`, for the sketch of all chapters together.
+pub fn merge_site_sketch(connection: &DbConnection, day: i64, sketch: &Sketch) -> QueryResult<()> {
+    insert_into(site_reader_sketches::table)
+        .values((
+            site_reader_sketches::day_start.eq(day),
+            site_reader_sketches::registers.eq(Sketch::default().as_bytes()),
+        ))
+        .on_conflict_do_nothing()
+        .execute(connection)?;
+    let mut stored = Sketch::from_bytes(
+        site_reader_sketches::table
+            .find(day)
+            .select(site_reader_sketches::registers)
+            .for_update()
+            .first(connection)?,
+    );
+    stored.merge(sketch);
+    diesel::update(site_reader_sketches::table.find(day))
+        .set(site_reader_sketches::registers.eq(stored.as_bytes()))
+        .execute(connection)?;
+    Ok(())
+}
+
+/// Merges the sketches of chapter `from` into those of chapter `into` and removes them from `from`.
+pub fn move_sketches(connection: &DbConnection, from: i32, into: i32) -> QueryResult<()> {
+    let sketches: Vec<(i64, Vec<u8>)> = reader_sketches::table
+        .filter(reader_sketches::chapter_id.eq(from))
+        .select((reader_sketches::day_start, reader_sketches::registers))
+        .load(connection)?;
+    for (day, registers) in sketches {
+        merge_chapter_sketch(connection, into, day, &Sketch::from_bytes(registers))?;
+    }
+    diesel::delete(reader_sketches::table.filter(reader_sketches::chapter_id.eq(from)))
+        .execute(connection)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch_of(readers: std::ops::Range<u64>) -> Sketch {
+        let mut sketch = Sketch::default();
+        for reader in readers {
+            sketch.insert(seahash::hash(&reader.to_le_bytes()));
+        }
+        sketch
+    }
+
+    fn assert_close(estimate: f64, actual: f64) {
+        assert!(
+            (estimate - actual).abs() <= actual * 0.1,
+            "estimated {} for {}",
+            estimate,
+            actual
+        );
+    }
+
+    #[test]
+    fn empty_sketch_estimates_zero() {
+        assert_eq!(Sketch::default().estimate(), 0.0);
+    }
+
+    #[test]
+    fn estimate_is_close_for_small_and_large_counts() {
+        assert_close(sketch_of(0..100).estimate(), 100.0);
+        assert_close(sketch_of(0..100_000).estimate(), 100_000.0);
+    }
+
+    #[test]
+    fn repeated_readers_are_counted_once() {
+        let mut sketch = sketch_of(0..1000);
+        sketch.merge(&sketch_of(0..1000));
+        assert_close(sketch.estimate(), 1000.0);
+    }
+
+    #[test]
+    fn merged_sketches_estimate_the_union() {
+        let mut sketch = sketch_of(0..6000);
+        sketch.merge(&sketch_of(4000..10_000));
+        assert_close(sketch.estimate(), 10_000.0);
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::{CacheControl, CacheDirective, ETag, EntityTag, Header, IfNoneMatch};
+use actix_web::web::Bytes;
+use actix_web::{HttpRequest, HttpResponse};
+use serde::Serialize;
+
+use crate::config::CacheConfig;
+use crate::error::WTError;
+use crate::metrics;
+
+/// What a cached response is derived from, so that writes can drop exactly the entries they affect.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CacheGroup {
+    Chapters,
+    Comments,
+}
+
+const GROUPS: usize = 2;
+
+impl CacheGroup {
+    fn index(self) -> usize {
+        match self {
+            CacheGroup::Chapters => 0,
+            CacheGroup::Comments => 1,
+        }
+    }
+}
+
+struct Entry {
+    body: Bytes,
+    etag: EntityTag,
+    expires: Instant,
+}
+
+/// Serialized responses of slowly changing read endpoints, keyed by endpoint and query and shared
+/// by all workers. Entries expire after `cache.ttl_seconds` or when their group is invalidated.
+pub struct ResponseCache {
+    enabled: bool,
+    ttl: Duration,
+    max_age: u32,
+    max_entries: usize,
+    entries: Mutex<HashMap<(CacheGroup, String), Entry>>,
+    /// Bumped by every invalidation, so that a response loaded before one is not stored after it.
+    generations: [AtomicU64; GROUPS],
+}
+
+impl ResponseCache {
+    pub fn new(config: &CacheConfig) -> ResponseCache {
+        ResponseCache {
+            enabled: config.enabled,
+            ttl: Duration::from_secs(config.ttl_seconds),
+            max_age: config.max_age_seconds,
+            max_entries: config.max_entries,
+            entries: Mutex::new(HashMap::new()),
+            generations: Default::default(),
+        }
+    }
+
+    /// Drops every entry of `group`. Called after writes that change what its endpoints return.
+    pub fn invalidate(&self, group: CacheGroup) {
+        self.generations[group.index()].fetch_add(1, Ordering::SeqCst);
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|(entry_group, _), _| *entry_group != group);
+    }
+
+    fn lookup(&self, group: CacheGroup, key: &str) -> Option<(Bytes, EntityTag)> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(&(group, key.to_owned()))
+            .filter(|entry| entry.expires > Instant::now())
+            .map(|entry| (entry.body.clone(), entry.etag.clone()))
+    }
+
+    fn store(&self, group: CacheGroup, key: String, generation: u64, entry: Entry) {
+        let mut entries = self.entries.lock().unwrap();
+        if self.generations[group.index()].load(Ordering::SeqCst) != generation {
+            return;
+        }
+        if entries.len() >= self.max_entries {
+            let now = Instant::now();
+            entries.retain(|_, entry| entry.expires > now);
+            if entries.len() >= self.max_entries {
+                // Keys include the page, so a full cache is more likely to hold pages that are
+                // rarely asked for than ones worth evicting the others for.
+                return;
+            }
+        }
+        entries.insert((group, key), entry);
+    }
+
+    /// Responds with the JSON of `load`, served from the cache while it is fresh, with an `ETag`
+    /// that lets clients revalidate and a 304 Not Modified when theirs still matches.
+    pub async fn respond<T, F>(
+        &self,
+        request: &HttpRequest,
+        group: CacheGroup,
+        key: String,
+        load: F,
+    ) -> Result<HttpResponse, WTError>
+    where
+        T: Serialize,
+        F: Future<Output = Result<T, WTError>>,
+    {
+        let cached = if self.enabled {
+            self.lookup(group, &key)
+        } else {
+            None
+        };
+        let (body, etag) = match cached {
+            Some(cached) => {
+                metrics::RESPONSE_CACHE_LOOKUPS
+                    .with_label_values(&["hit"])
+                    .inc();
+                cached
+            }
+            None => {
+                let generation = self.generations[group.index()].load(Ordering::SeqCst);
+                let body = Bytes::from(
+                    serde_json::to_vec(&load.await?)
+                        .map_err(|error| WTError::InternalError(Box::new(error)))?,
+                );
+                let etag = EntityTag::new_strong(format!("{:016x}", seahash::hash(&body)));
+                if self.enabled {
+                    metrics::RESPONSE_CACHE_LOOKUPS
+                        .with_label_values(&["miss"])
+                        .inc();
+                    let entry = Entry {
+                        body: body.clone(),
+                        etag: etag.clone(),
+                        expires: Instant::now() + self.ttl,
+                    };
+                    self.store(group, key, generation, entry);
+                }
+                (body, etag)
+            }
+        };
+        let not_modified = match IfNoneMatch::parse(request) {
+            Ok(IfNoneMatch::Any) => true,
+            Ok(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+            Err(_) => false,
+        };
+        let mut response = if not_modified {
+            HttpResponse::NotModified()
+        } else {
+            HttpResponse::Ok()
+        };
+        response
+            .insert_header(ETag(etag))
+            .insert_header(CacheControl(vec![
+                CacheDirective::Public,
+                CacheDirective::MaxAge(self.max_age),
+            ]));
+        Ok(if not_modified {
+            response.finish()
+        } else {
+            response.content_type("application/json").body(body)
+        })
+    }
+}
@@ -0,0 +1,115 @@
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::Bigint;
+use indoc::indoc;
+
+use crate::api::common;
+use crate::config::RetentionConfig;
+use crate::error::WTError;
+use crate::rollup::{self, DAY_MILLISECONDS};
+use crate::DbConnection;
+
+pub struct RetentionCounts {
+    pub visits: usize,
+    pub hourly_rollups: usize,
+}
+
+/// Runs `batch` in its own transaction until it affects fewer than `batch_size` rows.
+fn in_batches(
+    connection: &DbConnection,
+    batch_size: i64,
+    batch: impl Fn() -> QueryResult<usize>,
+) -> QueryResult<usize> {
+    let mut total = 0;
+    loop {
+        let affected = connection.transaction(&batch)?;
+        total += affected;
+        if (affected as i64) < batch_size {
+            return Ok(total);
+        }
+    }
+}
+
+/// The oldest timestamp that is kept when keeping `days` days, capped at `covered_until` so that
+/// nothing is removed before a rollup covers it.
+fn cutoff(now: i64, days: u32, covered_until: i64, what: &str) -> i64 {
+    let cutoff = now - i64::from(days) * DAY_MILLISECONDS;
+    if covered_until < cutoff {
+        tracing::warn!(
+            what,
+            covered_until,
+            cutoff,
+            "rollups lag behind the retention period; keeping rows they do not cover"
+        );
+    }
+    cutoff.min(covered_until)
+}
+
+/// Removes raw visits and hourly rollups that are past their retention period.
+pub fn apply(
+    connection: &DbConnection,
+    config: &RetentionConfig,
+    now: i64,
+) -> QueryResult<RetentionCounts> {
+    let watermarks = rollup::load_watermarks(connection)?;
+    let mut counts = RetentionCounts {
+        visits: 0,
+        hourly_rollups: 0,
+    };
+    if let Some(days) = config.visit_days {
+        let until = cutoff(now, days, watermarks.hourly, "visits");
+        let sql = if config.archive_visits {
+            indoc! {"
+                WITH moved AS (
+                    DELETE FROM visits WHERE id IN (
+                        SELECT id FROM visits WHERE timestamp < $1 LIMIT $2
+                    )
+                    RETURNING id, chapter_id, timestamp
+                )
+                INSERT INTO visits_archive (id, chapter_id, timestamp)
+                    SELECT id, chapter_id, timestamp FROM moved
+            "}
+        } else {
+            indoc! {"
+                DELETE FROM visits WHERE id IN (
+                    SELECT id FROM visits WHERE timestamp < $1 LIMIT $2
+                )
+            "}
+        };
+        counts.visits = in_batches(connection, config.batch_size, || {
+            sql_query(sql)
+                .bind::<Bigint, i64>(until)
+                .bind::<Bigint, i64>(config.batch_size)
+                .execute(connection)
+        })?;
+    }
+    if let Some(days) = config.hourly_rollup_days {
+        let until = cutoff(now, days, watermarks.daily, "hourly rollups");
+        counts.hourly_rollups = in_batches(connection, config.batch_size, || {
+            sql_query(indoc! {"
+                DELETE FROM visit_rollups_hourly WHERE (chapter_id, bucket_start) IN (
+                    SELECT chapter_id, bucket_start FROM visit_rollups_hourly
+                        WHERE bucket_start < $1 LIMIT $2
+                )
+            "})
+            .bind::<Bigint, i64>(until)
+            .bind::<Bigint, i64>(config.batch_size)
+            .execute(connection)
+        })?;
+    }
+    Ok(counts)
+}
+
+/// The job run by `PeriodicJob`.
+pub fn retention_job(connection: &DbConnection, config: &RetentionConfig) -> Result<(), WTError> {
+    let counts = apply(connection, config, common::get_current_timestamp())?;
+    if counts.visits > 0 || counts.hourly_rollups > 0 {
+        tracing::info!(
+            visits = counts.visits,
+            hourly_rollups = counts.hourly_rollups,
+            archived = config.archive_visits,
+            "expired rows removed"
+        );
+    }
+    Ok(())
+}
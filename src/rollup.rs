@@ -0,0 +1,226 @@
+use diesel::prelude::*;
+use diesel::sql_query;
+use diesel::sql_types::{Array, Bigint, Integer};
+use indoc::indoc;
+
+use crate::api::common;
+use crate::error::WTError;
+use crate::schema::rollup_watermarks;
+use crate::DbConnection;
+
+pub const HOUR_MILLISECONDS: i64 = 1000 * 3600;
+pub const DAY_MILLISECONDS: i64 = HOUR_MILLISECONDS * 24;
+
+const HOURLY: &str = "hourly";
+const DAILY: &str = "daily";
+
+/// Visits are timestamped before their transaction commits, so an hour is only rolled up once it
+/// has been over for this long.
+const GRACE_MILLISECONDS: i64 = 60 * 1000;
+
+pub fn floor(timestamp: i64, width: i64) -> i64 {
+    timestamp - timestamp.rem_euclid(width)
+}
+
+pub fn ceil(timestamp: i64, width: i64) -> i64 {
+    floor(timestamp + width - 1, width)
+}
+
+/// Visits before `hourly` are included in `visit_rollups_hourly`, and those before `daily` in
+/// `visit_rollups_daily`. `daily` never passes `hourly`.
+#[derive(Copy, Clone)]
+pub struct Watermarks {
+    pub hourly: i64,
+    pub daily: i64,
+}
+
+#[derive(Copy, Clone)]
+enum Lock {
+    None,
+    Share,
+    Update,
+}
+
+fn watermark(connection: &DbConnection, name: &str, lock: Lock) -> QueryResult<i64> {
+    let query = rollup_watermarks::table
+        .filter(rollup_watermarks::name.eq(name))
+        .select(rollup_watermarks::rolled_up_until);
+    match lock {
+        Lock::None => query.first(connection),
+        Lock::Share => query.for_share().first(connection),
+        Lock::Update => query.for_update().first(connection),
+    }
+}
+
+pub fn load_watermarks(connection: &DbConnection) -> QueryResult<Watermarks> {
+    Ok(Watermarks {
+        hourly: watermark(connection, HOURLY, Lock::None)?,
+        daily: watermark(connection, DAILY, Lock::None)?,
+    })
+}
+
+/// Like `load_watermarks`, but keeps rollups from advancing until the transaction ends, for
+/// writers that need to know which of their visits are too late to be rolled up.
+pub fn share_watermarks(connection: &DbConnection) -> QueryResult<Watermarks> {
+    Ok(Watermarks {
+        hourly: watermark(connection, HOURLY, Lock::Share)?,
+        daily: watermark(connection, DAILY, Lock::Share)?,
+    })
+}
+
+fn set_watermark(connection: &DbConnection, name: &str, rolled_up_until: i64) -> QueryResult<()> {
+    diesel::update(rollup_watermarks::table.filter(rollup_watermarks::name.eq(name)))
+        .set(rollup_watermarks::rolled_up_until.eq(rolled_up_until))
+        .execute(connection)?;
+    Ok(())
+}
+
+pub struct RollupCounts {
+    pub hourly_rows: usize,
+    pub daily_rows: usize,
+}
+
+/// Adds the visits of every hour and day that ended since the last run to the rollups. The
+/// watermarks are locked for the duration, so concurrent runs from several servers are harmless.
+pub fn roll_up(connection: &DbConnection, now: i64) -> QueryResult<RollupCounts> {
+    connection.transaction(|| {
+        let hourly_from = watermark(connection, HOURLY, Lock::Update)?;
+        let daily_from = watermark(connection, DAILY, Lock::Update)?;
+        let hourly_until = floor(now - GRACE_MILLISECONDS, HOUR_MILLISECONDS);
+        let mut counts = RollupCounts {
+            hourly_rows: 0,
+            daily_rows: 0,
+        };
+        if hourly_until > hourly_from {
+            counts.hourly_rows = sql_query(indoc! {"
+                INSERT INTO visit_rollups_hourly (chapter_id, bucket_start, visit_count)
+                    SELECT chapter_id, timestamp - timestamp % $3, count(1) FROM visits
+                        WHERE timestamp >= $1 AND timestamp < $2
+                        GROUP BY 1, 2
+                    ON CONFLICT (chapter_id, bucket_start) DO UPDATE
+                        SET visit_count = visit_rollups_hourly.visit_count + excluded.visit_count
+            "})
+            .bind::<Bigint, i64>(hourly_from)
+            .bind::<Bigint, i64>(hourly_until)
+            .bind::<Bigint, i64>(HOUR_MILLISECONDS)
+            .execute(connection)?;
+            set_watermark(connection, HOURLY, hourly_until)?;
+        }
+        let daily_until = floor(hourly_until.max(hourly_from), DAY_MILLISECONDS);
+        if daily_until > daily_from {
+            counts.daily_rows = sql_query(indoc! {"
+                INSERT INTO visit_rollups_daily (chapter_id, bucket_start, visit_count)
+                    SELECT chapter_id, bucket_start - bucket_start % $3, sum(visit_count)::int8
+                        FROM visit_rollups_hourly
+                        WHERE bucket_start >= $1 AND bucket_start < $2
+                        GROUP BY 1, 2
+                    ON CONFLICT (chapter_id, bucket_start) DO UPDATE
+                        SET visit_count = visit_rollups_daily.visit_count + excluded.visit_count
+            "})
+            .bind::<Bigint, i64>(daily_from)
+            .bind::<Bigint, i64>(daily_until)
+            .bind::<Bigint, i64>(DAY_MILLISECONDS)
+            .execute(connection)?;
+            set_watermark(connection, DAILY, daily_until)?;
+        }
+        Ok(counts)
+    })
+}
+
+/// Adds visits timestamped before the watermarks straight to the rollups, since the rollup job has
+/// already passed them. Requires the watermarks from `share_watermarks`.
+pub fn add_late_visits(
+    connection: &DbConnection,
+    chapter_id: i32,
+    timestamps: &[i64],
+    watermarks: Watermarks,
+) -> QueryResult<()> {
+    let late: Vec<i64> = timestamps
+        .iter()
+        .copied()
+        .filter(|timestamp| *timestamp < watermarks.hourly)
+        .collect();
+    if late.is_empty() {
+        return Ok(());
+    }
+    for (table, width, until) in &[
+        ("visit_rollups_hourly", HOUR_MILLISECONDS, watermarks.hourly),
+        ("visit_rollups_daily", DAY_MILLISECONDS, watermarks.daily),
+    ] {
+        sql_query(format!(
+            indoc! {"
+                INSERT INTO {table} (chapter_id, bucket_start, visit_count)
+                    SELECT $1, timestamp - timestamp % $3, count(1)
+                        FROM unnest($2) AS timestamp
+                        WHERE timestamp < $4
+                        GROUP BY 2
+                    ON CONFLICT (chapter_id, bucket_start) DO UPDATE
+                        SET visit_count = {table}.visit_count + excluded.visit_count
+            "},
+            table = table
+        ))
+        .bind::<Integer, i32>(chapter_id)
+        .bind::<Array<Bigint>, _>(&late)
+        .bind::<Bigint, i64>(*width)
+        .bind::<Bigint, i64>(*until)
+        .execute(connection)?;
+    }
+    Ok(())
+}
+
+/// Adds the rollups of chapter `from` onto chapter `into` and removes them from `from`.
+pub fn move_rollups(connection: &DbConnection, from: i32, into: i32) -> QueryResult<()> {
+    for table in &["visit_rollups_hourly", "visit_rollups_daily"] {
+        sql_query(format!(
+            indoc! {"
+                INSERT INTO {table} (chapter_id, bucket_start, visit_count)
+                    SELECT $2, bucket_start, visit_count FROM {table} WHERE chapter_id = $1
+                    ON CONFLICT (chapter_id, bucket_start) DO UPDATE
+                        SET visit_count = {table}.visit_count + excluded.visit_count
+            "},
+            table = table
+        ))
+        .bind::<Integer, i32>(from)
+        .bind::<Integer, i32>(into)
+        .execute(connection)?;
+        sql_query(format!("DELETE FROM {} WHERE chapter_id = $1", table))
+            .bind::<Integer, i32>(from)
+            .execute(connection)?;
+    }
+    Ok(())
+}
+
+/// The job run by `PeriodicJob`.
+pub fn roll_up_job(connection: &DbConnection) -> Result<(), WTError> {
+    let counts = roll_up(connection, common::get_current_timestamp())?;
+    tracing::debug!(
+        hourly_rows = counts.hourly_rows,
+        daily_rows = counts.daily_rows,
+        "visit rollup finished"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn floor_rounds_down_to_the_bucket_start() {
+        assert_eq!(floor(0, HOUR_MILLISECONDS), 0);
+        assert_eq!(floor(HOUR_MILLISECONDS - 1, HOUR_MILLISECONDS), 0);
+        assert_eq!(
+            floor(HOUR_MILLISECONDS, HOUR_MILLISECONDS),
+            HOUR_MILLISECONDS
+        );
+        assert_eq!(floor(-1, HOUR_MILLISECONDS), -HOUR_MILLISECONDS);
+    }
+
+    #[test]
+    fn ceil_rounds_up_to_the_next_bucket_start() {
+        assert_eq!(ceil(0, DAY_MILLISECONDS), 0);
+        assert_eq!(ceil(1, DAY_MILLISECONDS), DAY_MILLISECONDS);
+        assert_eq!(ceil(DAY_MILLISECONDS, DAY_MILLISECONDS), DAY_MILLISECONDS);
+        assert_eq!(ceil(-1, DAY_MILLISECONDS), 0);
+    }
+}
@@ -1,5 +1,12 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    chapter_aliases (relative_path) {
+        relative_path -> Varchar,
+        chapter_id -> Int4,
+    }
+}
+
 diesel::table! {
     chapters (id) {
         id -> Int4,
@@ -8,6 +15,15 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    comment_revisions (id) {
+        id -> Int8,
+        comment_id -> Int8,
+        content -> Varchar,
+        timestamp -> Int8,
+    }
+}
+
 diesel::table! {
     comments (id) {
         id -> Int8,
@@ -17,6 +33,7 @@ diesel::table! {
         deleted -> Bool,
         create_timestamp -> Int8,
         update_timestamp -> Int8,
+        parent_comment_id -> Nullable<Int8>,
     }
 }
 
@@ -26,6 +43,58 @@ diesel::table! {
         from_comment_id -> Int8,
         mentioned_user_id -> Int8,
         timestamp -> Int8,
+        read -> Bool,
+    }
+}
+
+diesel::table! {
+    moderation_logs (id) {
+        id -> Int8,
+        moderator_id -> Nullable<Int8>,
+        action -> Int2,
+        target_comment_id -> Nullable<Int8>,
+        target_user_id -> Nullable<Int8>,
+        timestamp -> Int8,
+        role -> Nullable<Int2>,
+        from_chapter_path -> Nullable<Varchar>,
+        into_chapter_path -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    page_patterns (pattern) {
+        pattern -> Varchar,
+    }
+}
+
+diesel::table! {
+    quarantined_pages (relative_path) {
+        relative_path -> Varchar,
+        visit_count -> Int8,
+        first_visit_timestamp -> Int8,
+        last_visit_timestamp -> Int8,
+    }
+}
+
+diesel::table! {
+    reader_sketches (chapter_id, day_start) {
+        chapter_id -> Int4,
+        day_start -> Int8,
+        registers -> Bytea,
+    }
+}
+
+diesel::table! {
+    rollup_watermarks (name) {
+        name -> Varchar,
+        rolled_up_until -> Int8,
+    }
+}
+
+diesel::table! {
+    site_reader_sketches (day_start) {
+        day_start -> Int8,
+        registers -> Bytea,
     }
 }
 
@@ -38,6 +107,23 @@ diesel::table! {
         display_name -> Varchar,
         disabled -> Bool,
         last_checked_mentions_timestamp -> Int8,
+        role -> Int2,
+    }
+}
+
+diesel::table! {
+    visit_rollups_daily (chapter_id, bucket_start) {
+        chapter_id -> Int4,
+        bucket_start -> Int8,
+        visit_count -> Int8,
+    }
+}
+
+diesel::table! {
+    visit_rollups_hourly (chapter_id, bucket_start) {
+        chapter_id -> Int4,
+        bucket_start -> Int8,
+        visit_count -> Int8,
     }
 }
 
@@ -49,6 +135,14 @@ diesel::table! {
     }
 }
 
+diesel::table! {
+    visits_archive (id) {
+        id -> Int8,
+        chapter_id -> Int4,
+        timestamp -> Int8,
+    }
+}
+
 diesel::table! {
     wtcup_2020_votes (id) {
         id -> Int8,
@@ -76,17 +170,35 @@ diesel::table! {
     }
 }
 
+diesel::joinable!(chapter_aliases -> chapters (chapter_id));
+diesel::joinable!(comment_revisions -> comments (comment_id));
 diesel::joinable!(mentions -> comments (from_comment_id));
 diesel::joinable!(mentions -> users (mentioned_user_id));
+diesel::joinable!(moderation_logs -> comments (target_comment_id));
+diesel::joinable!(reader_sketches -> chapters (chapter_id));
+diesel::joinable!(visit_rollups_daily -> chapters (chapter_id));
+diesel::joinable!(visit_rollups_hourly -> chapters (chapter_id));
+diesel::joinable!(visits_archive -> chapters (chapter_id));
 diesel::joinable!(wtcup_2021_votes -> users (user_id));
 diesel::joinable!(wtcup_2022_votes -> users (user_id));
 
 diesel::allow_tables_to_appear_in_same_query!(
+    chapter_aliases,
     chapters,
+    comment_revisions,
     comments,
     mentions,
+    moderation_logs,
+    page_patterns,
+    quarantined_pages,
+    reader_sketches,
+    rollup_watermarks,
+    site_reader_sketches,
     users,
+    visit_rollups_daily,
+    visit_rollups_hourly,
     visits,
+    visits_archive,
     wtcup_2020_votes,
     wtcup_2021_votes,
     wtcup_2022_votes,
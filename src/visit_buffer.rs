@@ -0,0 +1,363 @@
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+use std::time::{Duration, Instant};
+
+use actix::fut::{ready, wrap_future};
+use actix::{
+    Actor, ActorFutureExt, AsyncContext, AtomicResponse, Context, Handler, Message,
+    ResponseActFuture,
+};
+use diesel::insert_into;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, Pool};
+
+use crate::api::common;
+use crate::config::{AnalyticsConfig, PagesConfig};
+use crate::error::WTError;
+use crate::metrics;
+use crate::readers::{self, Sketch};
+use crate::rollup::{self, DAY_MILLISECONDS};
+use crate::schema::{chapters, quarantined_pages, visits};
+use crate::DbConnection;
+
+/// While the database is unavailable, visits are kept for retrying up to this many flushes worth.
+const MAX_RETAINED_FLUSHES: usize = 100;
+/// Rows per insert, well below PostgreSQL's limit of 65535 bind parameters.
+const INSERT_CHUNK_ROWS: usize = 10000;
+
+/// A visit to a path that is known to the page registry or already has a chapter.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RecordVisit {
+    pub relative_path: String,
+    pub timestamp: i64,
+    /// The day and salted hash from `ReaderSalt`, when unique readers are estimated.
+    pub reader: Option<(i64, u64)>,
+}
+
+/// A visit to a path that is unknown to the page registry while it quarantines unknown pages.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RecordQuarantinedVisit {
+    pub relative_path: String,
+    pub timestamp: i64,
+}
+
+#[derive(Default)]
+struct PathVisits {
+    timestamps: Vec<i64>,
+    /// Reader sketches by day.
+    readers: HashMap<i64, Sketch>,
+}
+
+struct QuarantinedVisits {
+    count: i64,
+    first_timestamp: i64,
+    last_timestamp: i64,
+}
+
+impl QuarantinedVisits {
+    fn add(&mut self, other: &QuarantinedVisits) {
+        self.count += other.count;
+        self.first_timestamp = self.first_timestamp.min(other.first_timestamp);
+        self.last_timestamp = self.last_timestamp.max(other.last_timestamp);
+    }
+}
+
+/// Where `quarantined_pages` stops growing.
+#[derive(Clone, Copy)]
+struct QuarantineLimits {
+    max_pages: i64,
+    days: u32,
+}
+
+/// Writes out everything buffered so far, resolving once it is in the database.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Flush;
+
+/// Accumulates visits per path so that `/stats/count` does not lock the chapter row on every
+/// request, and writes them in one transaction per flush.
+pub struct VisitBuffer {
+    db_pool: Pool<ConnectionManager<DbConnection>>,
+    interval: Duration,
+    max_events: usize,
+    quarantine_limits: QuarantineLimits,
+    visits: HashMap<String, PathVisits>,
+    quarantined: HashMap<String, QuarantinedVisits>,
+    buffered: usize,
+    flushing: usize,
+}
+
+impl VisitBuffer {
+    pub fn new(
+        db_pool: Pool<ConnectionManager<DbConnection>>,
+        config: &AnalyticsConfig,
+        pages: &PagesConfig,
+    ) -> Self {
+        VisitBuffer {
+            db_pool,
+            interval: Duration::from_secs(config.visit_flush_interval_seconds),
+            max_events: config.visit_flush_max_events,
+            quarantine_limits: QuarantineLimits {
+                max_pages: pages.quarantine_max_pages,
+                days: pages.quarantine_days,
+            },
+            visits: HashMap::new(),
+            quarantined: HashMap::new(),
+            buffered: 0,
+            flushing: 0,
+        }
+    }
+
+    fn update_depth(&self) {
+        metrics::VISIT_BUFFER_DEPTH.set((self.buffered + self.flushing) as i64);
+    }
+
+    /// Puts back the visits of a failed flush, unless too many are waiting already.
+    fn restore(
+        &mut self,
+        visits: HashMap<String, PathVisits>,
+        quarantined: HashMap<String, QuarantinedVisits>,
+        count: usize,
+    ) {
+        if self.buffered + count > self.max_events * MAX_RETAINED_FLUSHES {
+            metrics::VISITS_DROPPED.inc_by(count as u64);
+            tracing::error!(dropped = count, "visit buffer is full; dropping visits");
+            return;
+        }
+        for (relative_path, restored) in visits {
+            let path_visits = self.visits.entry(relative_path).or_default();
+            path_visits.timestamps.extend(restored.timestamps);
+            for (day, sketch) in restored.readers {
+                path_visits.readers.entry(day).or_default().merge(&sketch);
+            }
+        }
+        for (relative_path, restored) in quarantined {
+            self.add_quarantined(relative_path, restored);
+        }
+        self.buffered += count;
+    }
+
+    fn add_quarantined(&mut self, relative_path: String, visits: QuarantinedVisits) {
+        match self.quarantined.get_mut(&relative_path) {
+            Some(buffered) => buffered.add(&visits),
+            None => {
+                self.quarantined.insert(relative_path, visits);
+            }
+        }
+    }
+
+    /// Writes out the buffer. Run through `wait` so that flushes never overlap; visits recorded
+    /// meanwhile queue up in the mailbox.
+    fn flush(&mut self) -> ResponseActFuture<Self, ()> {
+        if self.buffered == 0 {
+            return Box::pin(ready(()));
+        }
+        let visits = mem::take(&mut self.visits);
+        let quarantined = mem::take(&mut self.quarantined);
+        let count = mem::replace(&mut self.buffered, 0);
+        self.flushing = count;
+        let db_pool = self.db_pool.clone();
+        let quarantine_limits = self.quarantine_limits;
+        let start = Instant::now();
+        let work = common::block(move || {
+            let result = db_pool.get().map_err(WTError::from).and_then(|connection| {
+                Ok(connection.transaction(|| {
+                    write_visits(&connection, &visits)?;
+                    write_quarantined(&connection, &quarantined, quarantine_limits)
+                })?)
+            });
+            (visits, quarantined, result)
+        });
+        Box::pin(wrap_future(work).map(move |result, actor: &mut Self, _| {
+            metrics::VISIT_FLUSH_DURATION.observe(start.elapsed().as_secs_f64());
+            actor.flushing = 0;
+            match result {
+                Ok((_, _, Ok(()))) => metrics::VISITS_RECORDED.inc_by(count as u64),
+                Ok((visits, quarantined, Err(error))) => {
+                    tracing::error!(
+                        visits = count,
+                        error = %error,
+                        cause = ?error.cause(),
+                        "failed to write buffered visits; retrying with the next flush"
+                    );
+                    actor.restore(visits, quarantined, count);
+                }
+                Err(error) => {
+                    metrics::VISITS_DROPPED.inc_by(count as u64);
+                    tracing::error!(dropped = count, error = %error, "visit flush panicked");
+                }
+            }
+            actor.update_depth();
+        }))
+    }
+}
+
+/// Inserts the visits and bumps the visit counts of their chapters, creating chapters as needed.
+fn write_visits(
+    connection: &DbConnection,
+    buffered: &HashMap<String, PathVisits>,
+) -> QueryResult<()> {
+    connection.transaction(|| {
+        let watermarks = rollup::share_watermarks(connection)?;
+        let mut resolved = Vec::with_capacity(buffered.len());
+        for (relative_path, path_visits) in buffered {
+            let chapter = match common::resolve_chapter(connection, relative_path)? {
+                Some(chapter) => chapter,
+                None => common::create_chapter(connection, relative_path)?,
+            };
+            resolved.push((chapter.id, path_visits));
+        }
+        // Updating chapters in a fixed order keeps concurrent flushes from deadlocking.
+        resolved.sort_by_key(|(chapter_id, _)| *chapter_id);
+        let mut site_readers: BTreeMap<i64, Sketch> = BTreeMap::new();
+        for (chapter_id, path_visits) in resolved {
+            let timestamps = &path_visits.timestamps;
+            for chunk in timestamps.chunks(INSERT_CHUNK_ROWS) {
+                let rows: Vec<_> = chunk
+                    .iter()
+                    .map(|timestamp| {
+                        (
+                            visits::chapter_id.eq(chapter_id),
+                            visits::timestamp.eq(timestamp),
+                        )
+                    })
+                    .collect();
+                insert_into(visits::table)
+                    .values(rows)
+                    .execute(connection)?;
+            }
+            rollup::add_late_visits(connection, chapter_id, timestamps, watermarks)?;
+            diesel::update(chapters::table.find(chapter_id))
+                .set(chapters::visit_count.eq(chapters::visit_count + timestamps.len() as i64))
+                .execute(connection)?;
+            let mut days: Vec<_> = path_visits.readers.iter().collect();
+            days.sort_by_key(|(day, _)| **day);
+            for (day, sketch) in days {
+                readers::merge_chapter_sketch(connection, chapter_id, *day, sketch)?;
+                site_readers.entry(*day).or_default().merge(sketch);
+            }
+        }
+        for (day, sketch) in &site_readers {
+            readers::merge_site_sketch(connection, *day, sketch)?;
+        }
+        Ok(())
+    })
+}
+
+/// Adds the quarantined visits to `quarantined_pages`. Paths that are not there yet are only added
+/// while there is room, if need be after removing those not visited for `limits.days`.
+fn write_quarantined(
+    connection: &DbConnection,
+    buffered: &HashMap<String, QuarantinedVisits>,
+    limits: QuarantineLimits,
+) -> QueryResult<()> {
+    if buffered.is_empty() {
+        return Ok(());
+    }
+    let mut stored: i64 = quarantined_pages::table.count().get_result(connection)?;
+    let mut expired = false;
+    let mut dropped = 0;
+    let mut paths: Vec<_> = buffered.iter().collect();
+    paths.sort_by_key(|(relative_path, _)| *relative_path);
+    for (relative_path, visits) in paths {
+        let updated = diesel::update(quarantined_pages::table.find(relative_path))
+            .set((
+                quarantined_pages::visit_count.eq(quarantined_pages::visit_count + visits.count),
+                quarantined_pages::last_visit_timestamp.eq(visits.last_timestamp),
+            ))
+            .execute(connection)?;
+        if updated > 0 {
+            continue;
+        }
+        if stored >= limits.max_pages && !expired {
+            let cutoff = common::get_current_timestamp() - limits.days as i64 * DAY_MILLISECONDS;
+            stored -= diesel::delete(
+                quarantined_pages::table.filter(quarantined_pages::last_visit_timestamp.lt(cutoff)),
+            )
+            .execute(connection)? as i64;
+            expired = true;
+        }
+        if stored >= limits.max_pages {
+            dropped += visits.count;
+            continue;
+        }
+        insert_into(quarantined_pages::table)
+            .values((
+                quarantined_pages::relative_path.eq(relative_path),
+                quarantined_pages::visit_count.eq(visits.count),
+                quarantined_pages::first_visit_timestamp.eq(visits.first_timestamp),
+                quarantined_pages::last_visit_timestamp.eq(visits.last_timestamp),
+            ))
+            .on_conflict(quarantined_pages::relative_path)
+            .do_update()
+            .set((
+                quarantined_pages::visit_count.eq(quarantined_pages::visit_count + visits.count),
+                quarantined_pages::last_visit_timestamp.eq(visits.last_timestamp),
+            ))
+            .execute(connection)?;
+        stored += 1;
+    }
+    if dropped > 0 {
+        tracing::warn!(
+            dropped,
+            max_pages = limits.max_pages,
+            "quarantined_pages is full; not counting visits to further unknown paths"
+        );
+    }
+    Ok(())
+}
+
+impl Actor for VisitBuffer {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        ctx.run_interval(self.interval, |actor, ctx| ctx.wait(actor.flush()));
+    }
+}
+
+impl Handler<RecordVisit> for VisitBuffer {
+    type Result = ();
+
+    fn handle(&mut self, visit: RecordVisit, ctx: &mut Context<Self>) {
+        let path_visits = self.visits.entry(visit.relative_path).or_default();
+        path_visits.timestamps.push(visit.timestamp);
+        if let Some((day, hash)) = visit.reader {
+            path_visits.readers.entry(day).or_default().insert(hash);
+        }
+        self.buffered += 1;
+        self.update_depth();
+        if self.buffered >= self.max_events {
+            ctx.wait(self.flush());
+        }
+    }
+}
+
+impl Handler<RecordQuarantinedVisit> for VisitBuffer {
+    type Result = ();
+
+    fn handle(&mut self, visit: RecordQuarantinedVisit, ctx: &mut Context<Self>) {
+        self.add_quarantined(
+            visit.relative_path,
+            QuarantinedVisits {
+                count: 1,
+                first_timestamp: visit.timestamp,
+                last_timestamp: visit.timestamp,
+            },
+        );
+        self.buffered += 1;
+        self.update_depth();
+        if self.buffered >= self.max_events {
+            ctx.wait(self.flush());
+        }
+    }
+}
+
+impl Handler<Flush> for VisitBuffer {
+    type Result = AtomicResponse<Self, ()>;
+
+    fn handle(&mut self, _: Flush, _: &mut Context<Self>) -> Self::Result {
+        AtomicResponse::new(self.flush())
+    }
+}
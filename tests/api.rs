@@ -0,0 +1,461 @@
+//! Requests against the HTTP API, backed by `DATABASE_URL`. Every test runs inside a transaction
+//! that is rolled back. They are ignored by default; run them with `cargo test -- --ignored`.
+
+use std::sync::Arc;
+
+use actix::Actor;
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App};
+use diesel::prelude::*;
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection, Pool};
+use diesel::{insert_into, Connection};
+use serde_json::{json, Value};
+
+use wt_analytics::api;
+use wt_analytics::bot_filter::BotFilter;
+use wt_analytics::config::Config;
+use wt_analytics::models::Comment;
+use wt_analytics::pages::Pages;
+use wt_analytics::rate_limit::RateLimiter;
+use wt_analytics::response_cache::ResponseCache;
+use wt_analytics::schema::{comments, users, wtcup_2022_votes};
+use wt_analytics::visit_buffer::VisitBuffer;
+use wt_analytics::{AppState, DbConnection};
+
+/// `ErrorCode::UserDisabled`.
+const USER_DISABLED: u64 = 11;
+/// `ErrorCode::ValidationFailed`.
+const VALIDATION_FAILED: u64 = 18;
+
+#[derive(Debug)]
+struct TestTransaction;
+
+impl CustomizeConnection<DbConnection, r2d2::Error> for TestTransaction {
+    fn on_acquire(&self, connection: &mut DbConnection) -> Result<(), r2d2::Error> {
+        connection
+            .begin_test_transaction()
+            .map_err(r2d2::Error::QueryError)
+    }
+}
+
+/// The state of a server whose pool holds a single connection that never commits, so that the
+/// test and the handlers see each other's writes and nothing outlives the test.
+fn test_state() -> web::Data<AppState> {
+    dotenv::dotenv().ok();
+    let url = std::env::var("DATABASE_URL").expect("DATABASE_URL must be set for these tests.");
+    let db_pool = Pool::builder()
+        .max_size(1)
+        .connection_customizer(Box::new(TestTransaction))
+        .build(ConnectionManager::<DbConnection>::new(url))
+        .expect("Failed to connect to DATABASE_URL.");
+    wt_analytics::run_migrations(&db_pool.get().unwrap()).unwrap();
+    let mut config = Config::default();
+    config.event.vote_start_timestamp = 0;
+    config.event.vote_end_timestamp = i64::MAX;
+    let pages = Arc::new(Pages::load(&config.pages, &[]).unwrap());
+    let visit_buffer = VisitBuffer::new(db_pool.clone(), &config.analytics, &config.pages).start();
+    web::Data::new(AppState {
+        rate_limiter: Arc::new(RateLimiter::new(&config.rate_limit)),
+        bot_filter: BotFilter::new(&config.bot_filter).unwrap(),
+        response_cache: ResponseCache::new(&config.cache),
+        pages,
+        visit_buffer,
+        reader_salt: None,
+        funnel_chapters: Vec::new(),
+        db_pool,
+        config,
+    })
+}
+
+/// Inserts a user and returns their token.
+fn insert_user(state: &AppState, user_name: &str, disabled: bool) -> String {
+    let token = api::user::generate_token();
+    insert_into(users::table)
+        .values((
+            users::token.eq(&token),
+            users::user_name.eq(user_name),
+            users::display_name.eq(user_name),
+            users::disabled.eq(disabled),
+        ))
+        .execute(&state.db_pool.get().unwrap())
+        .unwrap();
+    token
+}
+
+fn comments_of(state: &AppState, token: &str) -> Vec<Comment> {
+    comments::table
+        .inner_join(users::table)
+        .select(comments::all_columns)
+        .filter(users::token.eq(token))
+        .order_by(comments::id)
+        .load(&state.db_pool.get().unwrap())
+        .unwrap()
+}
+
+/// Posts `body` as JSON and reads the JSON response.
+macro_rules! post {
+    ($app:expr, $path:expr, $body:expr $(,)?) => {{
+        let request = test::TestRequest::post()
+            .uri($path)
+            .set_json($body)
+            .to_request();
+        let response: Value = test::call_and_read_body_json(&$app, request).await;
+        response
+    }};
+}
+
+macro_rules! test_app {
+    ($state:expr) => {
+        test::init_service(
+            App::new()
+                .app_data($state.clone())
+                .service(api::user::get_service())
+                .service(api::comment::get_service())
+                .service(api::event::get_service())
+                .service(api::notification::get_service()),
+        )
+        .await
+    };
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn send_stores_the_comment() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_send", false);
+    let response = post!(
+        app,
+        "/comment/send",
+        json!({"token": token, "relative_path": "test/send", "content": "Hello"}),
+    );
+    assert_eq!(response["success"], true);
+    let comments = comments_of(&state, &token);
+    assert_eq!(comments.len(), 1);
+    assert_eq!(comments[0].content, "Hello");
+    assert!(!comments[0].deleted);
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn send_rejects_disabled_users() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_send_disabled", true);
+    let response = post!(
+        app,
+        "/comment/send",
+        json!({"token": token, "relative_path": "test/send", "content": "Hello"}),
+    );
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], USER_DISABLED);
+    assert!(comments_of(&state, &token).is_empty());
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn delete_marks_own_comments_deleted() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_delete", false);
+    let other_token = insert_user(&state, "test_delete_other", false);
+    post!(
+        app,
+        "/comment/send",
+        json!({"token": token, "relative_path": "test/delete", "content": "Hello"}),
+    );
+    let comment_id = comments_of(&state, &token)[0].id;
+    let response = post!(
+        app,
+        "/comment/delete",
+        json!({"token": other_token, "comment_id": comment_id}),
+    );
+    assert_eq!(response["success"], false);
+    let response = post!(
+        app,
+        "/comment/delete",
+        json!({"token": token, "comment_id": comment_id}),
+    );
+    assert_eq!(response["success"], true);
+    assert!(comments_of(&state, &token)[0].deleted);
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn delete_rejects_disabled_users() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_delete_disabled", false);
+    post!(
+        app,
+        "/comment/send",
+        json!({"token": token, "relative_path": "test/delete", "content": "Hello"}),
+    );
+    let comment_id = comments_of(&state, &token)[0].id;
+    diesel::update(users::table.filter(users::token.eq(&token)))
+        .set(users::disabled.eq(true))
+        .execute(&state.db_pool.get().unwrap())
+        .unwrap();
+    let response = post!(
+        app,
+        "/comment/delete",
+        json!({"token": token, "comment_id": comment_id}),
+    );
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], USER_DISABLED);
+    assert!(!comments_of(&state, &token)[0].deleted);
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn edit_keeps_the_previous_versions_as_revisions() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_edit", false);
+    post!(
+        app,
+        "/comment/send",
+        json!({"token": token, "relative_path": "test/edit", "content": "First"}),
+    );
+    let comment_id = comments_of(&state, &token)[0].id;
+    for content in ["Second", "Third"] {
+        let response = post!(
+            app,
+            "/comment/edit",
+            json!({"token": token, "comment_id": comment_id, "content": content}),
+        );
+        assert_eq!(response["success"], true);
+    }
+    assert_eq!(comments_of(&state, &token)[0].content, "Third");
+    let request = test::TestRequest::get()
+        .uri(&format!("/comment/getRevisions?comment_id={}", comment_id))
+        .to_request();
+    let revisions: Value = test::call_and_read_body_json(&app, request).await;
+    let bodies: Vec<&str> = revisions
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|revision| revision["body"].as_str().unwrap())
+        .collect();
+    assert_eq!(bodies, vec!["First", "Second"]);
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn revisions_of_deleted_comments_are_not_found() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_revisions_deleted", false);
+    post!(
+        app,
+        "/comment/send",
+        json!({"token": token, "relative_path": "test/edit", "content": "Hello"}),
+    );
+    let comment_id = comments_of(&state, &token)[0].id;
+    post!(
+        app,
+        "/comment/delete",
+        json!({"token": token, "comment_id": comment_id}),
+    );
+    for comment_id in [comment_id, i64::MAX] {
+        let request = test::TestRequest::get()
+            .uri(&format!("/comment/getRevisions?comment_id={}", comment_id))
+            .to_request();
+        let response = test::call_service(&app, request).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn edit_only_notifies_newly_mentioned_users() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_edit_author", false);
+    let first = insert_user(&state, "test_edit_first", false);
+    let second = insert_user(&state, "test_edit_second", false);
+    post!(
+        app,
+        "/comment/send",
+        json!({"token": token, "relative_path": "test/edit", "content": "@test_edit_first hi"}),
+    );
+    let comment_id = comments_of(&state, &token)[0].id;
+    let response = post!(
+        app,
+        "/comment/edit",
+        json!({
+            "token": token,
+            "comment_id": comment_id,
+            "content": "@test_edit_first @test_edit_second hi",
+        }),
+    );
+    assert_eq!(response["success"], true);
+    for (mentioned, count) in [(&first, 1), (&second, 1), (&token, 0)] {
+        let response = post!(app, "/user/init", json!({ "token": mentioned }));
+        assert_eq!(response["mentions"], count);
+    }
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn init_returns_the_profile() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_init", false);
+    let response = post!(app, "/user/init", json!({ "token": token }));
+    assert_eq!(response["success"], true);
+    assert_eq!(response["user_name"], "test_init");
+    assert_eq!(response["mentions"], 0);
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn init_rejects_disabled_users() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_init_disabled", true);
+    let response = post!(app, "/user/init", json!({ "token": token }));
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], USER_DISABLED);
+}
+
+/// The ratings `token`'s user has given in the WTCup vote.
+fn ratings_of(state: &AppState, token: &str) -> Vec<i16> {
+    wtcup_2022_votes::table
+        .inner_join(users::table)
+        .select(wtcup_2022_votes::rating)
+        .filter(users::token.eq(token))
+        .load(&state.db_pool.get().unwrap())
+        .unwrap()
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn vote_records_and_withdraws_ratings() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_vote", false);
+    let response = post!(
+        app,
+        "/event/voteWtcup",
+        json!({"token": token, "chapter_vote_id": 70, "rating": 4}),
+    );
+    assert_eq!(response["success"], true);
+    assert_eq!(ratings_of(&state, &token), vec![4]);
+    let response = post!(
+        app,
+        "/event/voteWtcup",
+        json!({"token": token, "chapter_vote_id": 70, "rating": 0}),
+    );
+    assert_eq!(response["success"], true);
+    assert!(ratings_of(&state, &token).is_empty());
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn vote_rejects_disabled_users() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_vote_disabled", true);
+    let response = post!(
+        app,
+        "/event/voteWtcup",
+        json!({"token": token, "chapter_vote_id": 70, "rating": 4}),
+    );
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], USER_DISABLED);
+    assert!(ratings_of(&state, &token).is_empty());
+}
+
+/// The ids of the notifications in a `/notification/list` response.
+fn notification_ids(response: &Value) -> Vec<i64> {
+    response["notifications"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|notification| notification["id"].as_i64().unwrap())
+        .collect()
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn notifications_are_paged_counted_and_marked_read() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_notified", false);
+    let sender = insert_user(&state, "test_notifier", false);
+    for content in [
+        "@test_notified one",
+        "@test_notified two",
+        "@test_notified three",
+    ] {
+        post!(
+            app,
+            "/comment/send",
+            json!({"token": sender, "relative_path": "test/notification", "content": content}),
+        );
+    }
+    let response = post!(app, "/user/init", json!({ "token": token }));
+    assert_eq!(response["mentions"], 3);
+
+    let first_page = post!(
+        app,
+        "/notification/list",
+        json!({"token": token, "limit": 2}),
+    );
+    let first_ids = notification_ids(&first_page);
+    assert_eq!(first_ids.len(), 2);
+    assert!(first_ids[0] > first_ids[1]);
+    assert_eq!(first_page["next_cursor"], first_ids[1]);
+    let last_page = post!(
+        app,
+        "/notification/list",
+        json!({"token": token, "limit": 2, "before_id": first_ids[1]}),
+    );
+    let last_ids = notification_ids(&last_page);
+    assert_eq!(last_ids.len(), 1);
+    assert!(last_ids[0] < first_ids[1]);
+    assert!(last_page["next_cursor"].is_null());
+
+    let response = post!(
+        app,
+        "/notification/markRead",
+        json!({"token": token, "ids": [first_ids[0]]}),
+    );
+    assert_eq!(response["success"], true);
+    let response = post!(app, "/user/init", json!({ "token": token }));
+    assert_eq!(response["mentions"], 2);
+    let unread = post!(
+        app,
+        "/notification/list",
+        json!({"token": token, "unread_only": true}),
+    );
+    assert_eq!(notification_ids(&unread), vec![first_ids[1], last_ids[0]]);
+
+    let response = post!(app, "/notification/markAllRead", json!({ "token": token }));
+    assert_eq!(response["success"], true);
+    let response = post!(app, "/user/init", json!({ "token": token }));
+    assert_eq!(response["mentions"], 0);
+    let all = post!(app, "/notification/list", json!({ "token": token }));
+    let notifications = all["notifications"].as_array().unwrap();
+    assert_eq!(notifications.len(), 3);
+    assert!(notifications
+        .iter()
+        .all(|notification| notification["read"] == true));
+}
+
+#[actix_rt::test]
+#[ignore = "needs DATABASE_URL"]
+async fn mark_read_rejects_too_many_ids() {
+    let state = test_state();
+    let app = test_app!(state);
+    let token = insert_user(&state, "test_mark_read", false);
+    let ids: Vec<i64> = (0..=state.config.comment.max_page_size).collect();
+    let response = post!(
+        app,
+        "/notification/markRead",
+        json!({"token": token, "ids": ids}),
+    );
+    assert_eq!(response["success"], false);
+    assert_eq!(response["code"], VALIDATION_FAILED);
+}